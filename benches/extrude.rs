@@ -0,0 +1,150 @@
+//! Times `tilext::extrude` on square sheets of increasing size, next to the
+//! splicing implementation it replaced, so the speedup can be reproduced.
+//!
+//! Run with `cargo bench`.
+
+#![allow(clippy::needless_return)]
+
+extern crate tilext;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use tilext::{Options, RGBA};
+
+fn make_sheet(size: usize) -> Vec<RGBA>
+{
+    (0..size * size).map(|i| RGBA { r: i as u8, g: (i >> 8) as u8, b: (i >> 16) as u8, a: 255 }).collect()
+}
+
+/// Sheets larger than this take too long with the old implementation
+const MAX_SPLICE_SIZE: usize = 1024;
+
+fn insert_pixels(buf: &mut Vec<RGBA>, pos: usize, mut pixels: Vec<RGBA>) -> usize
+{
+    let count = pixels.len();
+
+    if pos >= buf.len()
+    {
+        buf.append(&mut pixels);
+    }
+    else
+    {
+        buf.splice(pos..pos, pixels);
+    }
+
+    return count;
+}
+
+/// How extrude worked before it wrote the output in a single pass: gutters
+/// are spliced into a copy of the buffer one run at a time, then filled in.
+/// Only clamped gutters and inputs without margin, spacing or partial tiles.
+fn splice_extrude(buffer: &[RGBA], width: usize, height: usize, options: &Options) -> Vec<RGBA>
+{
+    let mut image = buffer.to_vec();
+    let (tile_width, tile_height, gutter) = (options.tile_width, options.tile_height, options.gutter);
+    let (columns, rows) = (width / tile_width, height / tile_height);
+
+    let new_width = width + columns * gutter * 2;
+    let new_height = height + rows * gutter * 2;
+
+    let mut inserted = 0;
+    for i in 0..buffer.len()
+    {
+        let pixel_x = i % width;
+
+        if pixel_x == 0
+        {
+            let row = i / width;
+
+            if row.is_multiple_of(tile_height)
+            {
+                if i == 0
+                {
+                    inserted += insert_pixels(&mut image, i + inserted, vec![RGBA::default(); new_width * gutter + gutter]);
+                    continue;
+                }
+
+                inserted += insert_pixels(&mut image, i + inserted, vec![RGBA::default(); new_width * gutter * 2]);
+            }
+
+            inserted += insert_pixels(&mut image, i + inserted, vec![RGBA::default(); gutter * 2]);
+        }
+        else if pixel_x.is_multiple_of(tile_width)
+        {
+            inserted += insert_pixels(&mut image, i + inserted, vec![RGBA::default(); gutter * 2]);
+        }
+    }
+
+    let pos = image.len();
+    insert_pixels(&mut image, pos, vec![RGBA::default(); new_width * gutter + gutter]);
+    assert!(image.len() == new_width * new_height);
+
+    for tile_y in (0..new_height).step_by(tile_height + gutter * 2)
+    {
+        for tile_x in (0..new_width).step_by(tile_width + gutter * 2)
+        {
+            for y in 0..tile_height + gutter * 2
+            {
+                let inner_y = y.saturating_sub(gutter).min(tile_height - 1);
+
+                for x in 0..tile_width + gutter * 2
+                {
+                    let inner_x = x.saturating_sub(gutter).min(tile_width - 1);
+                    image[(tile_y + y) * new_width + tile_x + x] = image[(tile_y + gutter + inner_y) * new_width + tile_x + gutter + inner_x];
+                }
+            }
+        }
+    }
+
+    return image;
+}
+
+/// Runs `f` until at least `min_time` has passed, returning the average time per run
+fn time<F: FnMut()>(mut f: F, min_time: Duration) -> Duration
+{
+    let start = Instant::now();
+    let mut runs = 0;
+
+    while runs == 0 || start.elapsed() < min_time
+    {
+        f();
+        runs += 1;
+    }
+
+    return start.elapsed() / runs;
+}
+
+fn main()
+{
+    let tile_size = 16;
+
+    for &size in &[256, 512, 1024, 2048, 4096]
+    {
+        let sheet = make_sheet(size);
+
+        for &gutter in &[1, 2]
+        {
+            let mut options = Options::new(tile_size, tile_size);
+            options.gutter = gutter;
+
+            let t = time(|| { black_box(tilext::extrude(black_box(&sheet), size, size, &options).unwrap()); },
+                         Duration::from_millis(500));
+
+            let before = if size <= MAX_SPLICE_SIZE
+            {
+                assert!(splice_extrude(&sheet, size, size, &options) == tilext::extrude(&sheet, size, size, &options).unwrap().image.buffer);
+
+                let t = time(|| { black_box(splice_extrude(black_box(&sheet), size, size, &options)); }, Duration::from_millis(500));
+                format!("{:>10.3} ms", t.as_secs_f64() * 1000.0)
+            }
+            else
+            {
+                format!("{:>13}", "(skipped)")
+            };
+
+            println!("extrude {0}x{0}, {1}px tiles, {2}px gutter: {3:>10.3} ms (before: {4})",
+                size, tile_size, gutter, t.as_secs_f64() * 1000.0, before);
+        }
+    }
+}
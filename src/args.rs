@@ -0,0 +1,1475 @@
+//! Command line arguments, and the tilext.toml project file
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+
+use glob::{self, Pattern};
+use lodepng::ColorType;
+use tilext::{Error, ExtrudeMode, Options, PartialTiles, RGBA};
+use tilext::png::OutputColor;
+
+use config_file::{self, ConfigFile};
+
+/// When to back up inputs that are overwritten in place
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backup
+{
+    Never,
+    /// Only if there's no backup yet, so the first one (holding the
+    /// original image) is kept
+    Once,
+    /// Every time, refusing to replace an existing backup without --force
+    Always,
+    /// Every time, to the next free {stem}_backupN.png
+    Numbered
+}
+
+/// Description of the tiles of an output, written next to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Manifest
+{
+    /// Pixel rects and UVs of each tile, as JSON
+    Json,
+    /// TexturePacker's JSON (hash) format, with a frame for each tile
+    TexturePackerHash,
+    /// TexturePacker's JSON (array) format
+    TexturePackerArray,
+    /// Rust source with the tile rects and UVs as constants
+    Rust
+}
+
+const MANIFESTS: &[Manifest] = &[Manifest::Json, Manifest::TexturePackerHash, Manifest::TexturePackerArray, Manifest::Rust];
+
+impl Manifest
+{
+    /// Name of the format for --manifest
+    pub fn name(&self) -> &'static str
+    {
+        match *self
+        {
+            Manifest::Json => "json",
+            Manifest::TexturePackerHash => "texturepacker-hash",
+            Manifest::TexturePackerArray => "texturepacker-array",
+            Manifest::Rust => "rust"
+        }
+    }
+
+    /// Extension of the manifest file, which is named after the output
+    pub fn extension(&self) -> &'static str
+    {
+        match *self
+        {
+            Manifest::Json | Manifest::TexturePackerHash | Manifest::TexturePackerArray => "json",
+            Manifest::Rust => "rs"
+        }
+    }
+}
+
+/// What happens to the ancillary chunks of the input
+#[derive(Debug, Clone)]
+pub struct Metadata
+{
+    pub strip: bool,
+    pub srgb: Option<u8>,
+    pub gamma: Option<u32>,
+    pub text: Vec<(String, String)>
+}
+
+/// Tile layout and output naming, which a project file can set per file
+#[derive(Debug, Clone)]
+pub struct FileSettings
+{
+    pub options: Options,
+    pub output_suffix: String,
+    pub output_dir: Option<PathBuf>,
+    pub output_name: String
+}
+
+pub struct Config
+{
+    /// Settings for files that no override matches
+    pub file: FileSettings,
+    /// Settings for files matching the pattern of an [[override]] in the
+    /// project file. The last match wins.
+    pub overrides: Vec<(Pattern, FileSettings)>,
+    pub output_color: OutputColor,
+    pub metadata: Metadata,
+    pub backup: Backup,
+    /// Replace existing backups, and Godot TileSets without --godot-merge
+    pub force: bool,
+    /// Extrude images that are marked or look extruded
+    pub allow_reextrude: bool,
+    pub marker: bool,
+    /// Write a Tiled tileset next to each output
+    pub tsx: bool,
+    /// Directories of Tiled maps and tilesets to point at the outputs
+    pub update_tiled: Vec<PathBuf>,
+    /// Write a Godot TileSet resource next to each output
+    pub godot: bool,
+    /// Update the atlas source of an existing TileSet resource instead of
+    /// replacing it
+    pub godot_merge: bool,
+    pub manifests: Vec<Manifest>,
+    /// Move the UVs in manifests half a pixel inwards
+    pub half_texel_inset: bool,
+    /// Template of the frame names in TexturePacker manifests
+    pub frame_name: String,
+    /// Names of the tiles by index, for constants in Rust manifests
+    pub tile_names: Vec<String>,
+    pub strip: bool,
+    pub cache_path: Option<PathBuf>,
+    pub watch: bool,
+    pub jobs: usize,
+    /// Files, directories and patterns as given, for --watch to find new files
+    pub inputs: Vec<String>,
+    pub recursive: bool,
+    pub excludes: Vec<Pattern>,
+    pub input_paths: Vec<PathBuf>
+}
+
+/// The path relative to the working directory, without `.` components, or
+/// None if it lies elsewhere
+fn relative_to_cwd(path: &Path) -> Option<PathBuf>
+{
+    let relative = if path.is_relative()
+    {
+        path.to_path_buf()
+    }
+    else
+    {
+        path.strip_prefix(env::current_dir().ok()?).ok()?.to_path_buf()
+    };
+
+    if !relative.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return None;
+    }
+
+    return Some(relative.components().filter(|&c| c != Component::CurDir).collect());
+}
+
+/// Whether a pattern matches a path (as given or relative to the working
+/// directory), or just its file name
+fn matches(pattern: &Pattern, path: &Path) -> bool
+{
+    pattern.matches_path(path)
+        || relative_to_cwd(path).is_some_and(|relative| pattern.matches_path(&relative))
+        || path.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+}
+
+/// Folder of the input relative to the working directory, so --output-dir can
+/// mirror it. Empty if the input lies elsewhere.
+fn relative_dir(input_path: &Path) -> PathBuf
+{
+    input_path.parent().and_then(relative_to_cwd).unwrap_or_default()
+}
+
+impl Config
+{
+    /// Settings for one input file
+    pub fn settings_for(&self, path: &Path) -> &FileSettings
+    {
+        self.overrides.iter().rev()
+            .find(|(pattern, _)| matches(pattern, path))
+            .map_or(&self.file, |(_, settings)| settings)
+    }
+
+    pub fn output_path(&self, input_path: &Path) -> Result<PathBuf, Error>
+    {
+        let stem = input_path.file_stem().ok_or_else(|| Error::Write("Invalid path".into()))?.to_string_lossy();
+        let ext = input_path.extension().map(|e| e.to_string_lossy()).unwrap_or_default();
+        let settings = self.settings_for(input_path);
+        let name = expand_output_name(&settings.output_name, &stem, &ext, &settings.output_suffix, &settings.options)?;
+
+        let path = match settings.output_dir
+        {
+            Some(ref dir) => dir.join(relative_dir(input_path)).join(name),
+            None => input_path.with_file_name(name)
+        };
+        return Ok(path);
+    }
+
+    /// Inputs minus those that are the output of another input, such as the
+    /// a_extruded.png of a.png found again by a later run over the same folder
+    pub fn without_outputs(&self, mut paths: Vec<PathBuf>) -> Vec<PathBuf>
+    {
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+
+        let outputs: HashMap<PathBuf, PathBuf> = paths.iter()
+            .filter_map(|path| Some((canonical(&self.output_path(path).ok()?), canonical(path))))
+            .collect();
+
+        paths.retain(|path|
+        {
+            let path = canonical(path);
+            outputs.get(&path).is_none_or(|input| *input == path)
+        });
+
+        return paths;
+    }
+
+    /// Fails if two inputs would be written to the same output, as inputs
+    /// outside the working directory can be with --output-dir
+    pub fn check_outputs(&self, paths: &[PathBuf]) -> Result<(), Error>
+    {
+        let mut outputs = HashMap::with_capacity(paths.len());
+
+        for path in paths
+        {
+            let output_path = self.output_path(path)?;
+            let normalized: PathBuf = output_path.components().filter(|&c| c != Component::CurDir).collect();
+
+            if let Some(other) = outputs.insert(normalized, path)
+            {
+                return Err(Error::BadArguments(format!("Inputs {:?} and {:?} would both be written to {:?}", other, path, output_path)));
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+fn parse_extrude_mode(s: &str) -> Result<ExtrudeMode, Error>
+{
+    let mode = match s
+    {
+        "clamp" => ExtrudeMode::Clamp,
+        "wrap" => ExtrudeMode::Wrap,
+        "mirror" => ExtrudeMode::Mirror,
+        "transparent" => ExtrudeMode::Transparent,
+        s if s.starts_with("color:") =>
+        {
+            let hex = &s["color:".len()..];
+            if hex.len() != 8
+            {
+                return Err(Error::BadArguments(format!("Expected color as RRGGBBAA, got {}", hex)));
+            }
+
+            let channel = |i: usize| u8::from_str_radix(&hex[i*2..i*2 + 2], 16).map_err(
+                |e| Error::BadArguments(format!("{} (in color {})", e, hex))
+            );
+            ExtrudeMode::Color(RGBA { r: channel(0)?, g: channel(1)?, b: channel(2)?, a: channel(3)? })
+        },
+        s => return Err(Error::BadArguments(format!("Unknown extrude mode {} (expected clamp, wrap, mirror, transparent or color:RRGGBBAA)", s)))
+    };
+
+    return Ok(mode);
+}
+
+fn parse_output_color(s: &str) -> Result<OutputColor, Error>
+{
+    let color = match s
+    {
+        "keep" => OutputColor::Keep,
+        "auto" => OutputColor::Auto,
+        "grey8" => OutputColor::Convert(ColorType::GREY, 8),
+        "grey16" => OutputColor::Convert(ColorType::GREY, 16),
+        "grey-alpha8" => OutputColor::Convert(ColorType::GREY_ALPHA, 8),
+        "grey-alpha16" => OutputColor::Convert(ColorType::GREY_ALPHA, 16),
+        "rgb8" => OutputColor::Convert(ColorType::RGB, 8),
+        "rgb16" => OutputColor::Convert(ColorType::RGB, 16),
+        "rgba8" => OutputColor::Convert(ColorType::RGBA, 8),
+        "rgba16" => OutputColor::Convert(ColorType::RGBA, 16),
+        s => return Err(Error::BadArguments(format!(
+            "Unknown output format {} (expected keep, auto, grey8, grey16, grey-alpha8, grey-alpha16, rgb8, rgb16, rgba8 or rgba16)", s)))
+    };
+
+    return Ok(color);
+}
+
+fn parse_srgb_intent(s: &str) -> Result<u8, Error>
+{
+    let intent = match s
+    {
+        "perceptual" => 0,
+        "relative" => 1,
+        "saturation" => 2,
+        "absolute" => 3,
+        s => return Err(Error::BadArguments(format!("Unknown sRGB intent {} (expected perceptual, relative, saturation or absolute)", s)))
+    };
+
+    return Ok(intent);
+}
+
+fn parse_text(s: &str) -> Result<(String, String), Error>
+{
+    let (keyword, text) = s.split_once('=').ok_or_else(
+        || Error::BadArguments(format!("Expected KEYWORD=TEXT, got {}", s))
+    )?;
+
+    if keyword.is_empty() || keyword.len() > 79
+    {
+        return Err(Error::BadArguments(format!("Text keyword must be 1 to 79 characters long, got {:?}", keyword)));
+    }
+
+    return Ok((keyword.into(), text.into()));
+}
+
+/// Output file name used when --output-name isn't given
+pub const DEFAULT_OUTPUT_NAME: &str = "{stem}{suffix}.png";
+
+/// Frame names in TexturePacker manifests when --frame-name isn't given
+pub const DEFAULT_FRAME_NAME: &str = "{stem}_{index}";
+
+/// Fills in the `{name}` placeholders of a template, as listed in
+/// `placeholders`. `what` names the template in errors.
+fn expand_template(template: &str, what: &str, placeholders: &[(&str, String)]) -> Result<String, Error>
+{
+    let mut name = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{')
+    {
+        name.push_str(&rest[..start]);
+
+        let end = rest[start..].find('}').ok_or_else(
+            || Error::BadArguments(format!("Unclosed placeholder in {} {}", what, template))
+        )? + start;
+
+        let placeholder = &rest[start + 1..end];
+        match placeholders.iter().find(|(key, _)| *key == placeholder)
+        {
+            Some((_, value)) => name.push_str(value),
+
+            None =>
+            {
+                let mut expected: Vec<String> = placeholders.iter().map(|(key, _)| format!("{{{}}}", key)).collect();
+                let last = expected.pop().unwrap_or_default();
+                return Err(Error::BadArguments(format!(
+                    "Unknown placeholder {{{}}} in {} (expected {} or {})", placeholder, what, expected.join(", "), last)));
+            }
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    name.push_str(rest);
+    return Ok(name);
+}
+
+/// Fills in the placeholders of a --frame-name template
+pub fn expand_frame_name(template: &str, stem: &str, index: usize, column: usize, row: usize) -> Result<String, Error>
+{
+    expand_template(template, "frame name", &[
+        ("stem", stem.into()),
+        ("index", index.to_string()),
+        ("column", column.to_string()),
+        ("row", row.to_string())])
+}
+
+/// Fills in the placeholders of an --output-name template
+pub fn expand_output_name(template: &str, stem: &str, ext: &str, suffix: &str, options: &Options) -> Result<String, Error>
+{
+    let tile = if options.tile_width == options.tile_height
+    {
+        options.tile_width.to_string()
+    }
+    else
+    {
+        format!("{}x{}", options.tile_width, options.tile_height)
+    };
+
+    expand_template(template, "output name", &[
+        ("stem", stem.into()),
+        ("ext", ext.into()),
+        ("suffix", suffix.into()),
+        ("tile", tile),
+        ("gutter", options.gutter.to_string())])
+}
+
+/// Path of a backup of the input: {stem}_backup.png, or {stem}_backupN.png
+/// for numbered backups
+pub fn backup_path(input_path: &Path, number: Option<usize>) -> Result<PathBuf, Error>
+{
+    let mut backup_name = input_path.file_stem().ok_or_else(|| Error::Write("Invalid path".into()))?.to_os_string();
+    backup_name.push("_backup");
+    if let Some(number) = number
+    {
+        backup_name.push(number.to_string());
+    }
+    backup_name.push(".png");
+
+    return Ok(input_path.with_file_name(backup_name));
+}
+
+/// Whether a file is a backup that tilext made of an image next to it
+fn is_backup(path: &Path) -> bool
+{
+    let stem = match path.file_stem().and_then(|stem| stem.to_str())
+    {
+        Some(stem) => stem,
+        None => return false
+    };
+
+    let (original, number) = match stem.rfind("_backup")
+    {
+        Some(i) => (&stem[..i], &stem[i + "_backup".len()..]),
+        None => return false
+    };
+
+    if !number.chars().all(|c| c.is_ascii_digit())
+    {
+        return false;
+    }
+
+    return path.with_file_name(format!("{}.png", original)).is_file();
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool
+{
+    path.extension().is_some_and(|e| extensions.iter().any(|x| e.eq_ignore_ascii_case(x)))
+}
+
+/// Files with one of the extensions in a directory, sorted, and in its
+/// subdirectories if `recursive`
+fn find_in_dir(dir: &Path, recursive: bool, extensions: &[&str], found: &mut Vec<PathBuf>) -> Result<(), Error>
+{
+    let read_error = |e: std::io::Error| Error::BadArguments(format!("{} ({:?})", e, dir));
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).map_err(read_error)?
+    {
+        entries.push(entry.map_err(read_error)?.path());
+    }
+    entries.sort();
+
+    for path in entries
+    {
+        if path.is_dir()
+        {
+            if recursive
+            {
+                find_in_dir(&path, recursive, extensions, found)?;
+            }
+        }
+        else if has_extension(&path, extensions)
+        {
+            found.push(path);
+        }
+    }
+
+    return Ok(());
+}
+
+/// Expands the input arguments (files, directories and glob patterns) into
+/// the list of files to process, minus those matching an exclude pattern.
+/// Directories and patterns skip backups of other images, but files named
+/// outright are always kept.
+pub fn find_inputs(inputs: &[String], recursive: bool, excludes: &[Pattern]) -> Result<Vec<PathBuf>, Error>
+{
+    let mut found = Vec::new();
+
+    for input in inputs.iter().map(String::as_str)
+    {
+        let path = Path::new(input);
+
+        if path.is_dir()
+        {
+            let mut in_dir = Vec::new();
+            find_in_dir(path, recursive, &["png"], &mut in_dir)?;
+            found.extend(in_dir.into_iter().filter(|path| !is_backup(path)));
+        }
+        else if input.contains(['*', '?', '['])
+        {
+            let paths = glob::glob(input).map_err(|e| Error::BadArguments(format!("{} (in pattern {})", e, input)))?;
+            let count = found.len();
+
+            for entry in paths
+            {
+                let entry = entry.map_err(|e| Error::BadArguments(e.to_string()))?;
+                if entry.is_file() && !is_backup(&entry)
+                {
+                    found.push(entry);
+                }
+            }
+
+            if found.len() == count
+            {
+                println!("Warning: Pattern {} matches no files", input);
+            }
+        }
+        else
+        {
+            found.push(path.to_path_buf());
+        }
+    }
+
+    let excluded = |path: &Path| excludes.iter().any(|p| matches(p, path));
+
+    let mut inputs = Vec::new();
+    for path in found
+    {
+        if !excluded(&path) && !inputs.contains(&path)
+        {
+            inputs.push(path);
+        }
+    }
+
+    return Ok(inputs);
+}
+
+/// Tiled maps and tilesets in the --update-tiled directories
+pub fn find_tiled_files(dirs: &[PathBuf]) -> Result<Vec<PathBuf>, Error>
+{
+    let mut files = Vec::new();
+
+    for dir in dirs
+    {
+        let mut found = Vec::new();
+        find_in_dir(dir, true, &["tmx", "tsx"], &mut found)?;
+
+        for path in found
+        {
+            if !files.contains(&path)
+            {
+                files.push(path);
+            }
+        }
+    }
+
+    return Ok(files);
+}
+
+/// Cache file used by --incremental when --cache-file isn't given
+pub const DEFAULT_CACHE_FILE: &str = ".tilext-cache";
+
+/// What the command line asks for
+pub enum Command
+{
+    Run(Box<Config>),
+    Help,
+    Version
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArgsKey
+{
+    TileSize,
+    TileWidth,
+    TileHeight,
+    Gutter,
+    Extrude,
+    InputMargin,
+    InputSpacing,
+    Partial,
+    Strip,
+    OutputSuffix,
+    OutputDir,
+    OutputName,
+    OutputFormat,
+    Tsx,
+    UpdateTiled,
+    Godot,
+    GodotMerge,
+    ManifestFormat,
+    HalfTexelInset,
+    FrameName,
+    TileNames,
+    MetadataMode,
+    Srgb,
+    Gamma,
+    Text,
+    BackupPolicy,
+    Force,
+    AllowReextrude,
+    Marker,
+    Recursive,
+    Exclude,
+    Incremental,
+    CacheFile,
+    Watch,
+    Jobs,
+    ConfigPath,
+    Help,
+    Version
+}
+
+/// A command line option
+struct Flag
+{
+    key: ArgsKey,
+    long: &'static str,
+    short: Option<char>,
+    /// Name of the value in the help text, for options that take one
+    value: Option<&'static str>,
+    help: &'static str
+}
+
+use self::ArgsKey::*;
+
+const FLAGS: &[Flag] = &[
+    Flag { key: TileSize, long: "tile-size", short: Some('t'), value: Some("N"), help: "Tile width and height" },
+    Flag { key: TileWidth, long: "tile-width", short: None, value: Some("N"), help: "Tile width, if it differs from the height" },
+    Flag { key: TileHeight, long: "tile-height", short: None, value: Some("N"), help: "Tile height, if it differs from the width" },
+    Flag { key: Gutter, long: "gutter", short: Some('g'), value: Some("N"), help: "Gutter width on each side of a tile (default 1)" },
+    Flag { key: Extrude, long: "extrude-mode", short: None, value: Some("MODE"),
+        help: "What gutters are filled with: clamp, wrap, mirror, transparent or color:RRGGBBAA (default clamp)" },
+    Flag { key: InputMargin, long: "input-margin", short: None, value: Some("N"), help: "Margin around the tiles of the input" },
+    Flag { key: InputSpacing, long: "input-spacing", short: None, value: Some("N"), help: "Spacing between the tiles of the input" },
+    Flag { key: Partial, long: "partial-tiles", short: None, value: Some("error|crop|pad"),
+        help: "What to do when the image size isn't a multiple of the tile size (default error)" },
+    Flag { key: Strip, long: "strip", short: None, value: None, help: "Remove gutters instead of adding them" },
+    Flag { key: OutputSuffix, long: "output-suffix", short: Some('o'), value: Some("SUFFIX"), help: "Appended to the file name of outputs" },
+    Flag { key: OutputDir, long: "output-dir", short: None, value: Some("DIR"), help: "Write outputs here, mirroring the folders of the inputs" },
+    Flag { key: OutputName, long: "output-name", short: None, value: Some("TEMPLATE"),
+        help: "Output file name, with {stem}, {ext}, {suffix}, {tile} and {gutter} (default {stem}{suffix}.png)" },
+    Flag { key: OutputFormat, long: "output-format", short: None, value: Some("FORMAT"),
+        help: "keep, auto, grey8, grey16, grey-alpha8, grey-alpha16, rgb8, rgb16, rgba8 or rgba16 (default keep)" },
+    Flag { key: Tsx, long: "tsx", short: None, value: None, help: "Write or update a Tiled tileset (.tsx) next to each output" },
+    Flag { key: UpdateTiled, long: "update-tiled", short: None, value: Some("DIR"),
+        help: "Update the Tiled maps and tilesets in DIR that use the processed images (may be given more than once)" },
+    Flag { key: Godot, long: "godot", short: None, value: None, help: "Write a Godot 4 TileSet resource (.tres) next to each output" },
+    Flag { key: GodotMerge, long: "godot-merge", short: None, value: None,
+        help: "Like --godot, but update the layout in an existing .tres, keeping its physics, terrains and other data" },
+    Flag { key: ManifestFormat, long: "manifest", short: None, value: Some("FORMAT"),
+        help: "Write a manifest of the tiles next to each output: json (rects and UVs), texturepacker-hash, texturepacker-array or rust (constants)" },
+    Flag { key: HalfTexelInset, long: "half-texel-inset", short: None, value: None, help: "Move the UVs in manifests half a pixel inwards" },
+    Flag { key: FrameName, long: "frame-name", short: None, value: Some("TEMPLATE"),
+        help: "Frame names in TexturePacker manifests, with {stem}, {index}, {column} and {row} (default {stem}_{index})" },
+    Flag { key: TileNames, long: "tile-names", short: None, value: Some("PATH"),
+        help: "File with a tile name on each line, in tile order, for named constants in Rust manifests" },
+    Flag { key: MetadataMode, long: "metadata", short: None, value: Some("keep|strip"), help: "Keep or drop the ancillary chunks of the input (default keep)" },
+    Flag { key: Srgb, long: "srgb", short: None, value: Some("INTENT"), help: "Set the sRGB chunk: perceptual, relative, saturation or absolute" },
+    Flag { key: Gamma, long: "gamma", short: None, value: Some("GAMMA"), help: "Set the gAMA chunk from a display gamma such as 2.2" },
+    Flag { key: Text, long: "text", short: None, value: Some("KEY=TEXT"), help: "Set a tEXt chunk (may be given more than once)" },
+    Flag { key: BackupPolicy, long: "backup", short: None, value: Some("never|once|always|numbered"),
+        help: "When to back up inputs that are overwritten in place (default once)" },
+    Flag { key: Force, long: "force", short: None, value: None, help: "Replace existing backups, and Godot TileSets without --godot-merge" },
+    Flag { key: AllowReextrude, long: "allow-reextrude", short: None, value: None, help: "Extrude images that are marked or look extruded" },
+    Flag { key: Marker, long: "marker", short: None, value: None, help: "Record the tile size and gutter in a tEXt chunk of the output" },
+    Flag { key: Recursive, long: "recursive", short: Some('r'), value: None, help: "Look for PNG files in subdirectories of directory inputs" },
+    Flag { key: Exclude, long: "exclude", short: None, value: Some("PATTERN"), help: "Skip inputs whose path or name matches (may be given more than once)" },
+    Flag { key: Incremental, long: "incremental", short: None, value: None, help: "Skip files whose output is up to date" },
+    Flag { key: CacheFile, long: "cache-file", short: None, value: Some("PATH"), help: "Cache file for --incremental (default .tilext-cache)" },
+    Flag { key: Watch, long: "watch", short: Some('w'), value: None, help: "Keep running, and process inputs again when they change" },
+    Flag { key: Jobs, long: "jobs", short: Some('j'), value: Some("N"), help: "Number of files to process at once, 0 for one per core (default 1)" },
+    Flag { key: ConfigPath, long: "config", short: Some('c'), value: Some("PATH"), help: "Project file to read settings from (default tilext.toml, if there is one)" },
+    Flag { key: Help, long: "help", short: Some('h'), value: None, help: "Print this help" },
+    Flag { key: Version, long: "version", short: Some('V'), value: None, help: "Print the version" }
+];
+
+/// Options that an [[override]] in the project file may change
+const FILE_KEYS: &[ArgsKey] = &[TileSize, TileWidth, TileHeight, Gutter, Extrude, InputMargin, InputSpacing, Partial, OutputSuffix, OutputDir, OutputName];
+
+/// Help text listing every option
+pub fn help() -> String
+{
+    let mut help = format!("tilext {}\n", env!("CARGO_PKG_VERSION"));
+    help.push_str("Extrudes the tiles of PNG tilesets into gutters, so texture filtering doesn't bleed neighbouring tiles into each other.\n\n");
+    help.push_str("Usage: tilext [OPTIONS] <FILE|DIRECTORY|PATTERN>...\n\n");
+    help.push_str("Options:\n");
+
+    let names: Vec<String> = FLAGS.iter().map(|flag|
+    {
+        let short = flag.short.map(|c| format!("-{}, ", c)).unwrap_or_else(|| "    ".into());
+        let value = flag.value.map(|v| format!(" <{}>", v)).unwrap_or_default();
+        format!("{}--{}{}", short, flag.long, value)
+    }).collect();
+    let width = names.iter().map(|n| n.len()).max().unwrap_or(0);
+
+    for (flag, name) in FLAGS.iter().zip(&names)
+    {
+        help.push_str(&format!("  {:width$}  {}\n", name, flag.help, width = width));
+    }
+
+    help.push_str("\nOptions take their value as the next argument or after =, as in --gutter=2 or -g2. Arguments after -- are always inputs.\n");
+    help.push_str("A tilext.toml project file takes the same options by their long names, an inputs list, and [[override]] tables\n");
+    help.push_str("that set tile layout and output naming for the files matching a pattern. Command line options take precedence.\n");
+    help.push_str("Relative paths and patterns in a project file are relative to its folder.\n");
+    return help;
+}
+
+/// The option an argument names, and its value if given in the same argument
+/// (--key=value, -kvalue or -k=value)
+fn find_flag(arg: &str) -> Result<(&'static Flag, Option<&str>), Error>
+{
+    let unknown = || Error::BadArguments(format!("Unknown option {} (see --help)", arg.split('=').next().unwrap_or(arg)));
+
+    if let Some(long) = arg.strip_prefix("--")
+    {
+        let (name, value) = match long.split_once('=')
+        {
+            Some((name, value)) => (name, Some(value)),
+            None => (long, None)
+        };
+
+        let flag = FLAGS.iter().find(|f| f.long == name).ok_or_else(unknown)?;
+        return Ok((flag, value));
+    }
+
+    let mut chars = arg[1..].chars();
+    let short = chars.next().ok_or_else(unknown)?;
+    let rest = chars.as_str();
+
+    let flag = FLAGS.iter().find(|f| f.short == Some(short)).ok_or_else(unknown)?;
+    let value = match rest
+    {
+        "" => None,
+        rest => Some(rest.strip_prefix('=').unwrap_or(rest))
+    };
+    return Ok((flag, value));
+}
+
+fn parse_number(value: &str, flag: &Flag) -> Result<usize, Error>
+{
+    value.parse().map_err(|e| Error::BadArguments(format!("{} (after --{})", e, flag.long)))
+}
+
+/// Settings as the project file, its overrides and the command line build
+/// them up, before they're checked and turned into a [`Config`]
+#[derive(Clone)]
+struct Settings
+{
+    tile_width: Option<usize>,
+    tile_height: Option<usize>,
+    gutter: usize,
+    extrude_mode: ExtrudeMode,
+    input_margin: usize,
+    input_spacing: usize,
+    partial_tiles: PartialTiles,
+    output_suffix: String,
+    output_dir: Option<PathBuf>,
+    output_name: String,
+    output_color: OutputColor,
+    tsx: bool,
+    update_tiled: Vec<PathBuf>,
+    godot: bool,
+    godot_merge: bool,
+    manifests: Vec<Manifest>,
+    half_texel_inset: bool,
+    frame_name: String,
+    tile_names: Option<PathBuf>,
+    metadata: Metadata,
+    recursive: bool,
+    excludes: Vec<Pattern>,
+    strip: bool,
+    backup: Backup,
+    force: bool,
+    allow_reextrude: bool,
+    marker: bool,
+    incremental: bool,
+    watch: bool,
+    jobs: usize,
+    cache_path: Option<PathBuf>
+}
+
+impl Settings
+{
+    fn new() -> Settings
+    {
+        Settings
+        {
+            tile_width: None,
+            tile_height: None,
+            gutter: 1,
+            extrude_mode: ExtrudeMode::Clamp,
+            input_margin: 0,
+            input_spacing: 0,
+            partial_tiles: PartialTiles::Error,
+            output_suffix: String::new(),
+            output_dir: None,
+            output_name: DEFAULT_OUTPUT_NAME.into(),
+            output_color: OutputColor::Keep,
+            tsx: false,
+            update_tiled: Vec::new(),
+            godot: false,
+            godot_merge: false,
+            manifests: Vec::new(),
+            half_texel_inset: false,
+            frame_name: DEFAULT_FRAME_NAME.into(),
+            tile_names: None,
+            metadata: Metadata { strip: false, srgb: None, gamma: None, text: Vec::new() },
+            recursive: false,
+            excludes: Vec::new(),
+            strip: false,
+            backup: Backup::Once,
+            force: false,
+            allow_reextrude: false,
+            marker: false,
+            incremental: false,
+            watch: false,
+            jobs: 1,
+            cache_path: None
+        }
+    }
+
+    /// Sets an option. Options without a value are set by any value.
+    fn apply(&mut self, flag: &Flag, value: &str) -> Result<(), Error>
+    {
+        match flag.key
+        {
+            TileSize =>
+            {
+                let size = parse_number(value, flag)?;
+                self.tile_width = Some(size);
+                self.tile_height = Some(size);
+            },
+            TileWidth => self.tile_width = Some(parse_number(value, flag)?),
+            TileHeight => self.tile_height = Some(parse_number(value, flag)?),
+            Gutter => self.gutter = parse_number(value, flag)?,
+            Extrude => self.extrude_mode = parse_extrude_mode(value)?,
+            InputMargin => self.input_margin = parse_number(value, flag)?,
+            InputSpacing => self.input_spacing = parse_number(value, flag)?,
+
+            Partial =>
+            {
+                self.partial_tiles = match value
+                {
+                    "error" => PartialTiles::Error,
+                    "crop" => PartialTiles::Crop,
+                    "pad" => PartialTiles::Pad,
+                    s => return Err(Error::BadArguments(format!("Unknown partial tile behavior {} (expected error, crop or pad)", s)))
+                };
+            },
+
+            Strip => self.strip = true,
+            OutputSuffix => self.output_suffix = value.into(),
+            OutputDir => self.output_dir = Some(PathBuf::from(value)),
+            OutputName => self.output_name = value.into(),
+            OutputFormat => self.output_color = parse_output_color(value)?,
+            Tsx => self.tsx = true,
+            UpdateTiled => self.update_tiled.push(PathBuf::from(value)),
+            Godot => self.godot = true,
+
+            GodotMerge =>
+            {
+                self.godot = true;
+                self.godot_merge = true;
+            },
+
+            ManifestFormat =>
+            {
+                let manifest = *MANIFESTS.iter().find(|m| m.name() == value).ok_or_else(|| Error::BadArguments(format!(
+                    "Unknown manifest format {} (expected {})", value, MANIFESTS.iter().map(Manifest::name).collect::<Vec<_>>().join(", "))))?;
+
+                if let Some(other) = self.manifests.iter().find(|m| **m != manifest && m.extension() == manifest.extension())
+                {
+                    return Err(Error::BadArguments(format!(
+                        "Manifests {} and {} would both be written to .{} files", other.name(), manifest.name(), manifest.extension())));
+                }
+
+                if !self.manifests.contains(&manifest)
+                {
+                    self.manifests.push(manifest);
+                }
+            },
+
+            HalfTexelInset => self.half_texel_inset = true,
+            FrameName => self.frame_name = value.into(),
+            TileNames => self.tile_names = Some(PathBuf::from(value)),
+
+            MetadataMode =>
+            {
+                self.metadata.strip = match value
+                {
+                    "keep" => false,
+                    "strip" => true,
+                    s => return Err(Error::BadArguments(format!("Unknown metadata mode {} (expected keep or strip)", s)))
+                };
+            },
+
+            Srgb => self.metadata.srgb = Some(parse_srgb_intent(value)?),
+
+            Gamma =>
+            {
+                let gamma: f64 = value.parse().map_err(
+                    |e| Error::BadArguments(format!("{} (after --gamma)", e))
+                )?;
+                if gamma <= 0.0
+                {
+                    return Err(Error::BadArguments("Gamma must be greater than zero".into()));
+                }
+
+                // gAMA stores the file gamma (the inverse of the display gamma) times 100000
+                self.metadata.gamma = Some((100000.0 / gamma).round() as u32);
+            },
+
+            Text => self.metadata.text.push(parse_text(value)?),
+
+            BackupPolicy =>
+            {
+                self.backup = match value
+                {
+                    "never" => Backup::Never,
+                    "once" => Backup::Once,
+                    "always" => Backup::Always,
+                    "numbered" => Backup::Numbered,
+                    s => return Err(Error::BadArguments(format!("Unknown backup policy {} (expected never, once, always or numbered)", s)))
+                };
+            },
+
+            Force => self.force = true,
+            AllowReextrude => self.allow_reextrude = true,
+            Marker => self.marker = true,
+            Recursive => self.recursive = true,
+
+            Exclude =>
+            {
+                self.excludes.push(Pattern::new(value).map_err(
+                    |e| Error::BadArguments(format!("{} (in pattern {})", e, value))
+                )?);
+            },
+
+            Incremental => self.incremental = true,
+            CacheFile => self.cache_path = Some(PathBuf::from(value)),
+            Watch => self.watch = true,
+            Jobs => self.jobs = parse_number(value, flag)?,
+            ConfigPath | Help | Version => {}
+        }
+
+        return Ok(());
+    }
+
+    /// Sets the options of one layer (project file, override or command
+    /// line). --tile-size replaces the tile width and height of lower layers,
+    /// but explicit ones in the same layer take precedence.
+    fn apply_layer(&mut self, options: &[(&Flag, &str)]) -> Result<(), Error>
+    {
+        let (sizes, others): (Vec<_>, Vec<_>) = options.iter().partition(|(flag, _)| flag.key == TileSize);
+
+        for (flag, value) in sizes.into_iter().chain(others)
+        {
+            self.apply(flag, value)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Applies settings from the project file, where options without a value
+    /// are set with true and left alone with false
+    fn apply_from_file(&mut self, settings: &[(String, String)], file: &ConfigFile, file_keys_only: bool) -> Result<(), Error>
+    {
+        let in_file = |message: String| Error::BadArguments(format!("{} (in {:?})", message, file.path));
+        let mut options = Vec::new();
+
+        for (key, value) in settings
+        {
+            let flag = FLAGS.iter().find(|f| f.long == key).ok_or_else(|| in_file(format!("Unknown option {}", key)))?;
+
+            if [ConfigPath, Help, Version].contains(&flag.key) || (file_keys_only && !FILE_KEYS.contains(&flag.key))
+            {
+                return Err(in_file(format!("{} can't be set here", key)));
+            }
+
+            if flag.value.is_none()
+            {
+                match value.as_str()
+                {
+                    "true" => {},
+                    "false" => continue,
+                    _ => return Err(in_file(format!("Expected true or false for {}", key)))
+                }
+            }
+
+            options.push((flag, value.as_str()));
+        }
+
+        return self.apply_layer(&options).map_err(|e| match e
+        {
+            Error::BadArguments(message) => in_file(message),
+            e => e
+        });
+    }
+
+    fn file_settings(&self) -> Result<FileSettings, Error>
+    {
+        let tile_width = self.tile_width.ok_or_else(
+            || Error::BadArguments("No tile width specified (use --tile-size or --tile-width)".into())
+        )?;
+        let tile_height = self.tile_height.ok_or_else(
+            || Error::BadArguments("No tile height specified (use --tile-size or --tile-height)".into())
+        )?;
+
+        if tile_width == 0 || tile_height == 0
+        {
+            return Err(Error::BadArguments("Tile size must be greater than zero".into()));
+        }
+
+        let options = Options
+        {
+            tile_width,
+            tile_height,
+            gutter: self.gutter,
+            extrude_mode: self.extrude_mode,
+            input_margin: self.input_margin,
+            input_spacing: self.input_spacing,
+            partial_tiles: self.partial_tiles
+        };
+
+        // Catch bad placeholders before any file is processed
+        expand_output_name(&self.output_name, "", "", &self.output_suffix, &options)?;
+
+        if !self.output_suffix.is_empty() && !self.output_name.contains("{suffix}")
+        {
+            return Err(Error::BadArguments(format!(
+                "Output suffix {} would be ignored, since output name {} has no {{suffix}}", self.output_suffix, self.output_name)));
+        }
+
+        return Ok(FileSettings
+        {
+            options,
+            output_suffix: self.output_suffix.clone(),
+            output_dir: self.output_dir.clone(),
+            output_name: self.output_name.clone()
+        });
+    }
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command, Error>
+{
+    //
+    // Command line
+    //
+
+    let mut options = Vec::<(&Flag, &str)>::new();
+    let mut inputs = Vec::<String>::new();
+    let mut config_path: Option<PathBuf> = None;
+
+    let mut options_ended = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next()
+    {
+        if options_ended || arg == "-" || !arg.starts_with('-')
+        {
+            inputs.push(arg.clone());
+            continue;
+        }
+
+        if arg == "--"
+        {
+            options_ended = true;
+            continue;
+        }
+
+        let (flag, inline_value) = find_flag(arg)?;
+        let value = match (flag.value, inline_value)
+        {
+            (Some(_), Some(value)) => value,
+            (Some(_), None) => args.next().ok_or_else(
+                || Error::BadArguments(format!("Expected a value after --{}", flag.long))
+            )?,
+            (None, Some(_)) => return Err(Error::BadArguments(format!("--{} doesn't take a value", flag.long))),
+            (None, None) => ""
+        };
+
+        match flag.key
+        {
+            Help => return Ok(Command::Help),
+            Version => return Ok(Command::Version),
+            ConfigPath => config_path = Some(PathBuf::from(value)),
+            _ => options.push((flag, value))
+        }
+    }
+
+    //
+    // Project file, which the command line takes precedence over
+    //
+
+    let default_path = Path::new(config_file::DEFAULT_PATH);
+    let file = match config_path
+    {
+        Some(ref path) => ConfigFile::load(path)?,
+        None if default_path.is_file() => ConfigFile::load(default_path)?,
+        None => ConfigFile::default()
+    };
+
+    let mut defaults = Settings::new();
+    defaults.apply_from_file(&file.settings, &file, false)?;
+
+    let mut settings = defaults.clone();
+    settings.apply_layer(&options)?;
+
+    // Inputs of the project file are used when none are given
+    if inputs.is_empty()
+    {
+        inputs = file.inputs.clone();
+    }
+
+    //
+    // Inputs
+    //
+
+    if inputs.is_empty()
+    {
+        return Err(Error::BadArguments("No file paths specified (see --help)".into()));
+    }
+
+    let input_paths = find_inputs(&inputs, settings.recursive, &settings.excludes)?;
+    if input_paths.is_empty()
+    {
+        return Err(Error::BadArguments("No input files found".into()));
+    }
+
+    if let Some(dir) = settings.update_tiled.iter().find(|dir| !dir.is_dir())
+    {
+        return Err(Error::BadArguments(format!("{:?} isn't a directory (after --update-tiled)", dir)));
+    }
+
+    let mut overrides = Vec::new();
+    for (pattern, override_settings) in &file.overrides
+    {
+        let mut settings = defaults.clone();
+        settings.apply_from_file(override_settings, &file, true)?;
+        let file_options: Vec<(&Flag, &str)> = options.iter().cloned().filter(|(flag, _)| FILE_KEYS.contains(&flag.key)).collect();
+        settings.apply_layer(&file_options)?;
+
+        overrides.push((pattern.clone(), settings.file_settings()?));
+    }
+
+    if settings.output_suffix.is_empty() && settings.output_dir.is_none() && settings.output_name == DEFAULT_OUTPUT_NAME
+    {
+        match settings.backup
+        {
+            Backup::Never => println!("Warning: No output suffix specified. Input file will be overwritten without a backup. (use --output-suffix if you meant to specify a suffix)"),
+            _ => println!("Warning: No output suffix specified. Input file will be overwritten, and a backup (with suffix _backup) will be made. (use --output-suffix if you meant to specify a suffix)")
+        }
+    }
+
+    let file_settings = settings.file_settings()?;
+    expand_frame_name(&settings.frame_name, "", 0, 0, 0)?;
+
+    let tile_names = match settings.tile_names
+    {
+        Some(ref path) =>
+        {
+            let text = fs::read_to_string(path).map_err(|e| Error::BadArguments(format!("{} ({:?})", e, path)))?;
+            let mut names: Vec<String> = text.lines().map(|line| line.trim().to_string()).collect();
+            while names.last().is_some_and(|name| name.is_empty())
+            {
+                names.pop();
+            }
+            names
+        },
+        None => Vec::new()
+    };
+
+    // --jobs 0 uses every core
+    let jobs = match settings.jobs
+    {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        jobs => jobs
+    };
+
+    // --cache-file implies --incremental
+    let cache_path = match settings.cache_path
+    {
+        None if settings.incremental => Some(PathBuf::from(DEFAULT_CACHE_FILE)),
+        cache_path => cache_path
+    };
+
+    let mut c = Config
+    {
+        file: file_settings,
+        overrides,
+        output_color: settings.output_color,
+        metadata: settings.metadata,
+        backup: settings.backup,
+        force: settings.force,
+        allow_reextrude: settings.allow_reextrude,
+        marker: settings.marker,
+        tsx: settings.tsx,
+        update_tiled: settings.update_tiled,
+        godot: settings.godot,
+        godot_merge: settings.godot_merge,
+        manifests: settings.manifests,
+        half_texel_inset: settings.half_texel_inset,
+        frame_name: settings.frame_name,
+        tile_names,
+        strip: settings.strip,
+        cache_path,
+        watch: settings.watch,
+        jobs,
+        inputs,
+        recursive: settings.recursive,
+        excludes: settings.excludes,
+        input_paths: Vec::new()
+    };
+
+    c.input_paths = c.without_outputs(input_paths);
+    c.check_outputs(&c.input_paths)?;
+    return Ok(Command::Run(Box::new(c)));
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn touch(dir: &Path, names: &[&str])
+    {
+        for name in names
+        {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String>
+    {
+        args.iter().map(|&arg| arg.into()).collect()
+    }
+
+    fn parse_config(a: &[&str]) -> Result<Config, Error>
+    {
+        match parse_args(&args(a))?
+        {
+            Command::Run(config) => Ok(*config),
+            _ => panic!("Expected a run of {:?}", a)
+        }
+    }
+
+    #[test]
+    fn expand_output_names()
+    {
+        let mut options = Options::new(16, 16);
+        options.gutter = 2;
+
+        assert_eq!(expand_output_name(DEFAULT_OUTPUT_NAME, "a", "png", "_x", &options).unwrap(), "a_x.png");
+        assert_eq!(expand_output_name("{stem}_{tile}_g{gutter}.{ext}", "a", "PNG", "", &options).unwrap(), "a_16_g2.PNG");
+
+        options.tile_height = 8;
+        assert_eq!(expand_output_name("{tile}", "a", "png", "", &options).unwrap(), "16x8");
+        assert_eq!(expand_output_name("plain.png", "a", "png", "", &options).unwrap(), "plain.png");
+
+        match expand_output_name("{stem}{size}.png", "a", "png", "", &options)
+        {
+            Err(Error::BadArguments(message)) => assert!(message.contains("{size}") && message.contains("{tile} or {gutter}"), "{}", message),
+            result => panic!("{:?}", result)
+        }
+        assert!(expand_output_name("{stem", "a", "png", "", &options).is_err());
+
+        assert_eq!(expand_frame_name("{stem}-{row}-{column}-{index}", "tiles", 5, 1, 2).unwrap(), "tiles-2-1-5");
+        assert!(expand_frame_name("{ext}", "tiles", 0, 0, 0).is_err());
+    }
+
+    #[test]
+    fn output_names_use_the_suffix()
+    {
+        assert!(parse_config(&["-t", "4", "-o", "_x", "a.png"]).is_ok());
+        assert!(parse_config(&["-t", "4", "--output-name", "{stem}_{tile}.png", "a.png"]).is_ok());
+
+        match parse_config(&["-t", "4", "-o", "_x", "--output-name", "{stem}_{tile}.png", "a.png"])
+        {
+            Err(Error::BadArguments(message)) => assert!(message.contains("_x would be ignored"), "{}", message),
+            result => panic!("{:?}", result.err())
+        }
+    }
+
+    #[test]
+    fn colliding_outputs_fail()
+    {
+        let config = parse_config(&["-t", "4", "--output-dir", "out", "a.png"]).unwrap();
+        assert_eq!(config.output_path(Path::new("a.png")).unwrap(), Path::new("out/a.png"));
+        assert_eq!(config.output_path(Path::new("./art/a.png")).unwrap(), Path::new("out/art/a.png"));
+
+        assert!(config.check_outputs(&[PathBuf::from("a.png"), PathBuf::from("art/a.png")]).is_ok());
+        assert!(config.check_outputs(&[PathBuf::from("art/a.png"), PathBuf::from("./art/a.png")]).is_err());
+
+        // Inputs outside the working directory all land in the output directory
+        match config.check_outputs(&[PathBuf::from("../x/a.png"), PathBuf::from("../y/a.png")])
+        {
+            Err(Error::BadArguments(message)) => assert!(message.contains("would both be written to"), "{}", message),
+            result => panic!("{:?}", result)
+        }
+
+        let config = parse_config(&["-t", "4", "--output-name", "atlas.png", "a.png"]).unwrap();
+        assert!(config.check_outputs(&[PathBuf::from("x/a.png"), PathBuf::from("y/a.png")]).is_ok());
+        assert!(config.check_outputs(&[PathBuf::from("x/a.png"), PathBuf::from("x/b.png")]).is_err());
+    }
+
+    #[test]
+    fn find_inputs_skips_backups_unless_named()
+    {
+        let dir = ::test_dir("backups");
+        touch(&dir, &["a.png", "a_backup.png", "a_backup2.png", "b_backup.png", "notes.txt"]);
+
+        let names = |paths: Vec<PathBuf>| -> Vec<String>
+        {
+            paths.iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect()
+        };
+
+        // b_backup.png has no b.png, so it isn't a backup
+        let in_dir = find_inputs(&[dir.to_string_lossy().into_owned()], false, &[]).unwrap();
+        assert_eq!(names(in_dir), ["a.png", "b_backup.png"]);
+
+        let pattern = dir.join("*.png").to_string_lossy().into_owned();
+        assert_eq!(names(find_inputs(&[pattern], false, &[]).unwrap()), ["a.png", "b_backup.png"]);
+
+        let named = dir.join("a_backup.png").to_string_lossy().into_owned();
+        assert_eq!(names(find_inputs(&[named], false, &[]).unwrap()), ["a_backup.png"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn bad_arguments<T>(result: Result<T, Error>) -> String
+    {
+        match result
+        {
+            Err(Error::BadArguments(message)) => message,
+            Err(e) => panic!("Expected bad arguments, got {:?}", e),
+            Ok(_) => panic!("Expected bad arguments")
+        }
+    }
+
+    #[test]
+    fn options_take_values_in_every_form()
+    {
+        for a in &[&["-g", "2", "a.png"][..], &["-g2", "a.png"], &["-g=2", "a.png"], &["--gutter", "2", "a.png"], &["--gutter=2", "a.png"]]
+        {
+            let mut a = a.to_vec();
+            a.extend(&["-t", "4"]);
+            assert_eq!(parse_config(&a).unwrap().file.options.gutter, 2, "{:?}", a);
+        }
+
+        assert_eq!(parse_config(&["--output-suffix=_x", "-t", "4", "a.png"]).unwrap().file.output_suffix, "_x");
+        assert_eq!(parse_config(&["-o=", "-t", "4", "a.png"]).unwrap().file.output_suffix, "");
+
+        assert!(bad_arguments(parse_config(&["a.png", "-t"])).contains("Expected a value after --tile-size"));
+    }
+
+    #[test]
+    fn switches_refuse_values()
+    {
+        assert!(parse_config(&["--force", "-t", "4", "a.png"]).unwrap().force);
+        assert!(!parse_config(&["-t", "4", "a.png"]).unwrap().force);
+
+        assert!(bad_arguments(parse_config(&["--force=1", "-t", "4", "a.png"])).contains("--force doesn't take a value"));
+        assert!(bad_arguments(parse_config(&["-rx", "-t", "4", "a.png"])).contains("--recursive doesn't take a value"));
+    }
+
+    #[test]
+    fn double_dash_ends_options()
+    {
+        let config = parse_config(&["-t", "4", "--", "-a.png", "--force"]).unwrap();
+        assert_eq!(config.inputs, ["-a.png", "--force"]);
+        assert!(!config.force);
+
+        // A lone dash is an input, not an option
+        assert_eq!(parse_config(&["-t", "4", "-"]).unwrap().inputs, ["-"]);
+    }
+
+    #[test]
+    fn unknown_options_fail()
+    {
+        assert_eq!(bad_arguments(parse_config(&["--nope", "a.png"])), "Unknown option --nope (see --help)");
+        assert_eq!(bad_arguments(parse_config(&["--nope=1", "a.png"])), "Unknown option --nope (see --help)");
+        assert_eq!(bad_arguments(parse_config(&["-Z", "a.png"])), "Unknown option -Z (see --help)");
+
+        // Long names need both dashes: this is -g with a value of "utter"
+        assert!(bad_arguments(parse_config(&["-gutter", "a.png"])).ends_with("(after --gutter)"));
+    }
+
+    #[test]
+    fn help_and_version_stop_parsing()
+    {
+        assert!(matches!(parse_args(&args(&["--help"])), Ok(Command::Help)));
+        assert!(matches!(parse_args(&args(&["-h", "--nope"])), Ok(Command::Help)));
+        assert!(matches!(parse_args(&args(&["--version"])), Ok(Command::Version)));
+        assert!(matches!(parse_args(&args(&["-t", "4", "-V"])), Ok(Command::Version)));
+
+        // Options before them are still checked
+        assert!(bad_arguments(parse_args(&args(&["--nope", "--help"]))).starts_with("Unknown option"));
+    }
+
+    /// A project file in a folder of its own, with art/a.png, art/big.png and
+    /// art/old.png
+    fn project(name: &str, toml: &str) -> (PathBuf, String)
+    {
+        let dir = ::test_dir(name);
+        fs::create_dir(dir.join("art")).unwrap();
+        touch(&dir.join("art"), &["a.png", "big.png", "old.png"]);
+
+        let path = dir.join("tilext.toml");
+        fs::write(&path, toml).unwrap();
+        return (dir, path.to_string_lossy().into_owned());
+    }
+
+    #[test]
+    fn command_line_beats_overrides_beat_the_file()
+    {
+        let (dir, path) = project("layers", r#"
+            inputs = ["art"]
+            tile-size = 16
+            tile-height = 12
+            gutter = 3
+            output-suffix = "_x"
+
+            [[override]]
+            pattern = "art/big*.png"
+            tile-width = 32
+            gutter = 4
+        "#);
+        let a = dir.join("art/a.png");
+        let big = dir.join("art/big.png");
+
+        let sizes = |config: &Config, path: &Path| -> (usize, usize, usize)
+        {
+            let options = &config.settings_for(path).options;
+            (options.tile_width, options.tile_height, options.gutter)
+        };
+
+        // --tile-height beats --tile-size in the same layer, and an override
+        // starts from the file's settings
+        let config = parse_config(&["-c", &path]).unwrap();
+        assert_eq!(sizes(&config, &a), (16, 12, 3));
+        assert_eq!(sizes(&config, &big), (32, 12, 4));
+
+        let config = parse_config(&["-c", &path, "-g", "5"]).unwrap();
+        assert_eq!(sizes(&config, &a), (16, 12, 5));
+        assert_eq!(sizes(&config, &big), (32, 12, 5));
+
+        // --tile-size in a later layer beats --tile-width and --tile-height
+        // in earlier ones
+        let config = parse_config(&["-c", &path, "-t", "8"]).unwrap();
+        assert_eq!(sizes(&config, &a), (8, 8, 3));
+        assert_eq!(sizes(&config, &big), (8, 8, 4));
+
+        // Within the command line, --tile-width wins in either order
+        for sizes_given in &[["-t", "8", "--tile-width", "20"], ["--tile-width", "20", "-t", "8"]]
+        {
+            let mut args = sizes_given.to_vec();
+            args.extend(&["-c", &path]);
+            let config = parse_config(&args).unwrap();
+            assert_eq!(sizes(&config, &a), (20, 8, 3));
+            assert_eq!(sizes(&config, &big), (20, 8, 4));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn project_file_paths_are_relative_to_it()
+    {
+        let (dir, path) = project("paths", r#"
+            inputs = ["art"]
+            tile-size = 16
+            output-dir = "out"
+            update-tiled = ["maps"]
+            tile-names = "names.txt"
+            cache-file = "cache.txt"
+            exclude = "art/old*.png"
+
+            [[override]]
+            pattern = "art/big*.png"
+            tile-size = 32
+        "#);
+        fs::create_dir(dir.join("maps")).unwrap();
+        fs::write(dir.join("names.txt"), "grass\nwater\n").unwrap();
+
+        let config = parse_config(&["-c", &path]).unwrap();
+        assert_eq!(config.input_paths, [dir.join("art/a.png"), dir.join("art/big.png")]);
+        assert_eq!(config.settings_for(&dir.join("art/big.png")).options.tile_width, 32);
+        assert_eq!(config.file.output_dir, Some(dir.join("out")));
+        assert_eq!(config.update_tiled, [dir.join("maps")]);
+        assert_eq!(config.tile_names, ["grass", "water"]);
+        assert_eq!(config.cache_path, Some(dir.join("cache.txt")));
+
+        // Command line paths are still relative to the working directory
+        let config = parse_config(&["-c", &path, "--output-dir", "out"]).unwrap();
+        assert_eq!(config.file.output_dir, Some(PathBuf::from("out")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,478 @@
+//! Where each tile of an extruded or stripped image lies, for renderers that
+//! need the tile rectangles without recomputing them from the gutter layout
+
+use {Error, Options};
+
+/// A rectangle of pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect
+{
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize
+}
+
+/// Where a tile was in the input image, and where it is in the output
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tile
+{
+    /// Index of the tile, row by row
+    pub index: usize,
+    pub column: usize,
+    pub row: usize,
+    pub source: Rect,
+    /// The tile without its gutters
+    pub dest: Rect
+}
+
+/// Tiles of an output image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Atlas
+{
+    pub width: usize,
+    pub height: usize,
+    pub tile_width: usize,
+    pub tile_height: usize,
+    /// Gutter around each tile of the output, 0 for stripped images
+    pub gutter: usize,
+    pub columns: usize,
+    pub rows: usize,
+    pub tiles: Vec<Tile>
+}
+
+/// Normalized texture coordinates of a rectangle, as (left, top, right, bottom)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Uv
+{
+    pub u0: f64,
+    pub v0: f64,
+    pub u1: f64,
+    pub v1: f64
+}
+
+/// Escapes text for a JSON string, with the quotes
+pub(crate) fn json_string(s: &str) -> String
+{
+    let mut json = String::from("\"");
+    for c in s.chars()
+    {
+        match c
+        {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c)
+        }
+    }
+    json.push('"');
+    return json;
+}
+
+/// Rust constant name for a tile name, such as GRASS_TOP for "grass top"
+fn rust_constant(name: &str) -> String
+{
+    let mut constant: String = name.trim().chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+
+    if constant.starts_with(|c: char| c.is_ascii_digit())
+    {
+        constant.insert(0, '_');
+    }
+
+    return constant;
+}
+
+fn rect_json(rect: &Rect) -> String
+{
+    format!("{{ \"x\": {}, \"y\": {}, \"width\": {}, \"height\": {} }}", rect.x, rect.y, rect.width, rect.height)
+}
+
+impl Atlas
+{
+    fn new(width: usize, height: usize, options: &Options, gutter: usize, source: &dyn Fn(usize, usize) -> Rect) -> Atlas
+    {
+        let columns = width / (options.tile_width + gutter * 2);
+        let rows = height / (options.tile_height + gutter * 2);
+        let mut tiles = Vec::with_capacity(columns * rows);
+
+        for row in 0..rows
+        {
+            for column in 0..columns
+            {
+                let dest = Rect
+                {
+                    x: column * (options.tile_width + gutter * 2) + gutter,
+                    y: row * (options.tile_height + gutter * 2) + gutter,
+                    width: options.tile_width,
+                    height: options.tile_height
+                };
+
+                tiles.push(Tile { index: row * columns + column, column, row, source: source(column, row), dest });
+            }
+        }
+
+        Atlas { width, height, tile_width: options.tile_width, tile_height: options.tile_height, gutter, columns, rows, tiles }
+    }
+
+    /// Tiles of a `width * height` image that [`extrude`](::extrude) made with
+    /// these options. Padded partial tiles reach past the input image.
+    pub fn extruded(width: usize, height: usize, options: &Options) -> Atlas
+    {
+        Atlas::new(width, height, options, options.gutter, &|column, row| Rect
+        {
+            x: ::tile_start(column, options.tile_width, options.input_margin, options.input_spacing),
+            y: ::tile_start(row, options.tile_height, options.input_margin, options.input_spacing),
+            width: options.tile_width,
+            height: options.tile_height
+        })
+    }
+
+    /// Tiles of a `width * height` image that [`strip`](::strip) made with
+    /// these options
+    pub fn stripped(width: usize, height: usize, options: &Options) -> Atlas
+    {
+        Atlas::new(width, height, options, 0, &|column, row| Rect
+        {
+            x: column * (options.tile_width + options.gutter * 2) + options.gutter,
+            y: row * (options.tile_height + options.gutter * 2) + options.gutter,
+            width: options.tile_width,
+            height: options.tile_height
+        })
+    }
+
+    /// Texture coordinates of a rectangle of the image. With
+    /// `half_texel_inset` each edge moves half a pixel inwards, so that
+    /// bilinear filtering never reaches past the rectangle.
+    pub fn uv(&self, rect: &Rect, half_texel_inset: bool) -> Uv
+    {
+        let inset = if half_texel_inset { 0.5 } else { 0.0 };
+        let (width, height) = (self.width as f64, self.height as f64);
+
+        Uv
+        {
+            u0: (rect.x as f64 + inset) / width,
+            v0: (rect.y as f64 + inset) / height,
+            u1: ((rect.x + rect.width) as f64 - inset) / width,
+            v1: ((rect.y + rect.height) as f64 - inset) / height
+        }
+    }
+
+    /// JSON manifest of the atlas, whose image is at `image` (relative to the
+    /// manifest)
+    pub fn to_json(&self, image: &str, half_texel_inset: bool) -> String
+    {
+        let mut json = String::from("{\n");
+        json.push_str(&format!("  \"image\": {},\n", json_string(image)));
+        json.push_str(&format!("  \"width\": {},\n  \"height\": {},\n", self.width, self.height));
+        json.push_str(&format!("  \"tile_width\": {},\n  \"tile_height\": {},\n", self.tile_width, self.tile_height));
+        json.push_str(&format!("  \"gutter\": {},\n", self.gutter));
+        json.push_str(&format!("  \"columns\": {},\n  \"rows\": {},\n", self.columns, self.rows));
+        json.push_str(&format!("  \"half_texel_inset\": {},\n", half_texel_inset));
+        json.push_str("  \"tiles\": [");
+
+        for (i, tile) in self.tiles.iter().enumerate()
+        {
+            let uv = self.uv(&tile.dest, half_texel_inset);
+
+            json.push_str(if i == 0 { "\n" } else { ",\n" });
+            json.push_str(&format!(
+                "    {{ \"index\": {}, \"column\": {}, \"row\": {}, \"source\": {}, \"dest\": {}, \"uv\": {{ \"u0\": {}, \"v0\": {}, \"u1\": {}, \"v1\": {} }} }}",
+                tile.index, tile.column, tile.row, rect_json(&tile.source), rect_json(&tile.dest), uv.u0, uv.v0, uv.u1, uv.v1));
+        }
+
+        json.push_str(if self.tiles.is_empty() { "]\n}\n" } else { "\n  ]\n}\n" });
+        return json;
+    }
+
+    /// TexturePacker JSON of the atlas, with a frame for each tile named
+    /// `names[tile.index]`, in the array format or else the hash format.
+    /// `format` is the pixel format of the image as TexturePacker names it,
+    /// such as RGBA8888, if it has a name for it.
+    pub fn to_texture_packer(&self, image: &str, names: &[String], format: Option<&str>, array: bool) -> String
+    {
+        let mut json = String::from(if array { "{\"frames\": [" } else { "{\"frames\": {" });
+
+        for (i, tile) in self.tiles.iter().enumerate()
+        {
+            let rect = &tile.dest;
+            let frame = format!(
+                "\"frame\": {{\"x\":{},\"y\":{},\"w\":{},\"h\":{}}},\n\t\"rotated\": false,\n\t\"trimmed\": false,\n\t\"spriteSourceSize\": {{\"x\":0,\"y\":0,\"w\":{},\"h\":{}}},\n\t\"sourceSize\": {{\"w\":{},\"h\":{}}}",
+                rect.x, rect.y, rect.width, rect.height, rect.width, rect.height, rect.width, rect.height);
+
+            json.push_str(if i == 0 { "\n" } else { ",\n" });
+            if array
+            {
+                json.push_str(&format!("{{\n\t\"filename\": {},\n\t{}\n}}", json_string(&names[tile.index]), frame));
+            }
+            else
+            {
+                json.push_str(&format!("{}:\n{{\n\t{}\n}}", json_string(&names[tile.index]), frame));
+            }
+        }
+
+        json.push_str(if array { "]," } else { "}," });
+        json.push_str(&format!("\n\"meta\": {{\n\t\"app\": \"tilext\",\n\t\"version\": \"{}\",\n\t\"image\": {},\n",
+            env!("CARGO_PKG_VERSION"), json_string(image)));
+        if let Some(format) = format
+        {
+            json.push_str(&format!("\t\"format\": {},\n", json_string(format)));
+        }
+        json.push_str(&format!("\t\"size\": {{\"w\":{},\"h\":{}}},\n\t\"scale\": \"1\"\n}}\n}}\n", self.width, self.height));
+        return json;
+    }
+
+    /// Rust source with the atlas as constants, for games that embed the
+    /// image with `include_bytes!`. Tiles with a name in `names` (by tile
+    /// index, empty for none) get a constant holding their index in a `tiles`
+    /// module.
+    pub fn to_rust(&self, image: &str, names: &[String], half_texel_inset: bool) -> Result<String, Error>
+    {
+        if names.len() > self.tiles.len()
+        {
+            return Err(Error::BadArguments(format!("{} tile names given for {} tiles", names.len(), self.tiles.len())));
+        }
+
+        let mut rust = format!("// Atlas {}, generated by tilext {}\n\n", image, env!("CARGO_PKG_VERSION"));
+        rust.push_str(&format!("pub const ATLAS_WIDTH: u32 = {};\npub const ATLAS_HEIGHT: u32 = {};\n", self.width, self.height));
+        rust.push_str(&format!("pub const TILE_WIDTH: u32 = {};\npub const TILE_HEIGHT: u32 = {};\n", self.tile_width, self.tile_height));
+        rust.push_str(&format!("pub const GUTTER: u32 = {};\n", self.gutter));
+        rust.push_str(&format!("pub const COLUMNS: u32 = {};\npub const ROWS: u32 = {};\n", self.columns, self.rows));
+        rust.push_str(&format!("pub const TILE_COUNT: usize = {};\n", self.tiles.len()));
+
+        rust.push_str("\n/// Tiles without their gutters, as [x, y, width, height] in pixels\n");
+        rust.push_str(&format!("pub const TILE_RECTS: [[u32; 4]; {}] = [\n", self.tiles.len()));
+        for tile in &self.tiles
+        {
+            let rect = &tile.dest;
+            rust.push_str(&format!("    [{}, {}, {}, {}],\n", rect.x, rect.y, rect.width, rect.height));
+        }
+        rust.push_str("];\n");
+
+        rust.push_str(if half_texel_inset { "\n/// Tiles as [u0, v0, u1, v1], half a pixel inside their edges\n" } else { "\n/// Tiles as [u0, v0, u1, v1]\n" });
+        rust.push_str(&format!("pub const TILE_UVS: [[f32; 4]; {}] = [\n", self.tiles.len()));
+        for tile in &self.tiles
+        {
+            let uv = self.uv(&tile.dest, half_texel_inset);
+            rust.push_str(&format!("    [{:?}, {:?}, {:?}, {:?}],\n", uv.u0 as f32, uv.v0 as f32, uv.u1 as f32, uv.v1 as f32));
+        }
+        rust.push_str("];\n");
+
+        let named: Vec<(usize, String)> = names.iter().enumerate()
+            .filter(|&(_, name)| !name.trim().is_empty())
+            .map(|(index, name)| (index, rust_constant(name)))
+            .collect();
+
+        if !named.is_empty()
+        {
+            rust.push_str("\n/// Indices of named tiles\npub mod tiles {\n");
+            for (i, &(index, ref constant)) in named.iter().enumerate()
+            {
+                if constant.chars().all(|c| c == '_')
+                {
+                    return Err(Error::BadArguments(format!("Tile name {:?} has no letters or digits for a constant name", names[index])));
+                }
+
+                if named[..i].iter().any(|(_, other)| other == constant)
+                {
+                    return Err(Error::BadArguments(format!("Several tiles are named {}", constant)));
+                }
+
+                rust.push_str(&format!("    pub const {}: usize = {};\n", constant, index));
+            }
+            rust.push_str("}\n");
+        }
+
+        return Ok(rust);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use {extrude, strip, PartialTiles, RGBA};
+
+    /// A 10x6 sheet of 4x4 tiles whose last column and row are partial, and
+    /// options to pad them with gutters of `gutter` pixels
+    fn padded_sheet(gutter: usize) -> (Vec<RGBA>, Options)
+    {
+        let mut options = Options::new(4, 4);
+        options.gutter = gutter;
+        options.partial_tiles = PartialTiles::Pad;
+
+        let sheet = (0..60).map(|i| RGBA { r: i as u8, g: 0, b: 100, a: 255 }).collect();
+        return (sheet, options);
+    }
+
+    /// Whether a rectangle of one image holds the same pixels as a rectangle
+    /// of another, where the first image has them
+    fn same_pixels(from: &[RGBA], from_width: usize, from_rect: &Rect, to: &[RGBA], to_width: usize, to_rect: &Rect) -> bool
+    {
+        let from_height = from.len() / from_width;
+
+        (0..from_rect.height).all(|y| (0..from_rect.width).all(|x|
+        {
+            let (from_x, from_y) = (from_rect.x + x, from_rect.y + y);
+            from_x >= from_width || from_y >= from_height
+                || from[from_y * from_width + from_x] == to[(to_rect.y + y) * to_width + to_rect.x + x]
+        }))
+    }
+
+    #[test]
+    fn tiles_of_extruded_and_stripped_images()
+    {
+        for gutter in 0..3
+        {
+            let (sheet, options) = padded_sheet(gutter);
+            let extruded = extrude(&sheet, 10, 6, &options).unwrap().image;
+            let atlas = Atlas::extruded(extruded.width, extruded.height, &options);
+
+            assert_eq!((atlas.columns, atlas.rows, atlas.gutter, atlas.tiles.len()), (3, 2, gutter, 6));
+
+            // The partial tile in the corner reaches past the input
+            let last = &atlas.tiles[5];
+            assert_eq!((last.index, last.column, last.row), (5, 2, 1));
+            assert_eq!(last.source, Rect { x: 8, y: 4, width: 4, height: 4 });
+            assert_eq!(last.dest, Rect { x: [8, 13, 18][gutter], y: [4, 7, 10][gutter], width: 4, height: 4 });
+
+            for tile in &atlas.tiles
+            {
+                assert_eq!(tile.source, Rect { x: tile.column * 4, y: tile.row * 4, width: 4, height: 4 });
+                assert!(same_pixels(&sheet, 10, &tile.source, &extruded.buffer, extruded.width, &tile.dest), "tile {}", tile.index);
+            }
+
+            // Stripping takes the tiles from where extruding put them
+            let stripped = strip(&extruded.buffer, extruded.width, extruded.height, &options).unwrap().image;
+            let stripped_atlas = Atlas::stripped(stripped.width, stripped.height, &options);
+            assert_eq!((stripped_atlas.columns, stripped_atlas.rows, stripped_atlas.gutter), (3, 2, 0));
+
+            for (tile, extruded_tile) in stripped_atlas.tiles.iter().zip(&atlas.tiles)
+            {
+                assert_eq!(tile.source, extruded_tile.dest);
+                assert_eq!(tile.dest, extruded_tile.source);
+                assert!(same_pixels(&extruded.buffer, extruded.width, &tile.source, &stripped.buffer, stripped.width, &tile.dest));
+            }
+        }
+    }
+
+    #[test]
+    fn uvs_with_and_without_half_texel_inset()
+    {
+        for gutter in 0..3
+        {
+            let size = 2 * (4 + gutter * 2);
+            let mut options = Options::new(4, 4);
+            options.gutter = gutter;
+            let atlas = Atlas::extruded(size, size, &options);
+            let size = size as f64;
+
+            let first = atlas.uv(&atlas.tiles[0].dest, false);
+            let g = gutter as f64;
+            assert_eq!(first, Uv { u0: g / size, v0: g / size, u1: (g + 4.0) / size, v1: (g + 4.0) / size });
+
+            let inset = atlas.uv(&atlas.tiles[0].dest, true);
+            assert_eq!(inset, Uv { u0: (g + 0.5) / size, v0: (g + 0.5) / size, u1: (g + 3.5) / size, v1: (g + 3.5) / size });
+
+            // The last tile ends a gutter away from the far edge
+            let last = atlas.uv(&atlas.tiles[3].dest, false);
+            assert_eq!((last.u1, last.v1), ((size - g) / size, (size - g) / size));
+        }
+    }
+
+    #[test]
+    fn json_manifest()
+    {
+        let mut options = Options::new(2, 1);
+        options.gutter = 1;
+        let atlas = Atlas::extruded(8, 3, &options);
+
+        assert_eq!(atlas.to_json("tiles \"x\".png", true), "{\n  \
+            \"image\": \"tiles \\\"x\\\".png\",\n  \
+            \"width\": 8,\n  \"height\": 3,\n  \
+            \"tile_width\": 2,\n  \"tile_height\": 1,\n  \
+            \"gutter\": 1,\n  \
+            \"columns\": 2,\n  \"rows\": 1,\n  \
+            \"half_texel_inset\": true,\n  \
+            \"tiles\": [\n    \
+            { \"index\": 0, \"column\": 0, \"row\": 0, \"source\": { \"x\": 0, \"y\": 0, \"width\": 2, \"height\": 1 }, \
+            \"dest\": { \"x\": 1, \"y\": 1, \"width\": 2, \"height\": 1 }, \"uv\": { \"u0\": 0.1875, \"v0\": 0.5, \"u1\": 0.3125, \"v1\": 0.5 } },\n    \
+            { \"index\": 1, \"column\": 1, \"row\": 0, \"source\": { \"x\": 2, \"y\": 0, \"width\": 2, \"height\": 1 }, \
+            \"dest\": { \"x\": 5, \"y\": 1, \"width\": 2, \"height\": 1 }, \"uv\": { \"u0\": 0.6875, \"v0\": 0.5, \"u1\": 0.8125, \"v1\": 0.5 } }\n  \
+            ]\n}\n");
+
+        assert!(Atlas::extruded(2, 2, &options).to_json("a.png", false).ends_with("\"tiles\": []\n}\n"));
+    }
+
+    #[test]
+    fn texture_packer_frames()
+    {
+        let mut options = Options::new(2, 1);
+        options.gutter = 1;
+        let atlas = Atlas::extruded(8, 3, &options);
+        let names = ["grass".to_string(), "water \"deep\"".to_string()];
+        let frame = |x| format!("\"frame\": {{\"x\":{},\"y\":1,\"w\":2,\"h\":1}},\n\t\"rotated\": false,\n\t\"trimmed\": false,\n\t\
+            \"spriteSourceSize\": {{\"x\":0,\"y\":0,\"w\":2,\"h\":1}},\n\t\"sourceSize\": {{\"w\":2,\"h\":1}}", x);
+        let meta = |format: &str| format!("\n\"meta\": {{\n\t\"app\": \"tilext\",\n\t\"version\": \"{}\",\n\t\"image\": \"tiles.png\",\n{}\
+            \t\"size\": {{\"w\":8,\"h\":3}},\n\t\"scale\": \"1\"\n}}\n}}\n", env!("CARGO_PKG_VERSION"), format);
+
+        assert_eq!(atlas.to_texture_packer("tiles.png", &names, Some("RGBA8888"), false), format!(
+            "{{\"frames\": {{\n\"grass\":\n{{\n\t{}\n}},\n\"water \\\"deep\\\"\":\n{{\n\t{}\n}}}},{}",
+            frame(1), frame(5), meta("\t\"format\": \"RGBA8888\",\n")));
+
+        assert_eq!(atlas.to_texture_packer("tiles.png", &names, None, true), format!(
+            "{{\"frames\": [\n{{\n\t\"filename\": \"grass\",\n\t{}\n}},\n{{\n\t\"filename\": \"water \\\"deep\\\"\",\n\t{}\n}}],{}",
+            frame(1), frame(5), meta("")));
+    }
+
+    #[test]
+    fn rust_constant_names()
+    {
+        assert_eq!(rust_constant("grass top"), "GRASS_TOP");
+        assert_eq!(rust_constant(" water-2 "), "WATER_2");
+        assert_eq!(rust_constant("1st"), "_1ST");
+        assert_eq!(rust_constant("héllo"), "H_LLO");
+    }
+
+    #[test]
+    fn rust_source()
+    {
+        let mut options = Options::new(2, 1);
+        options.gutter = 1;
+        let atlas = Atlas::extruded(8, 3, &options);
+        let names = |names: &[&str]| -> Vec<String> { names.iter().map(|&name| name.into()).collect() };
+
+        assert_eq!(atlas.to_rust("tiles.png", &names(&["", "1st water"]), false).unwrap(), format!(
+            "// Atlas tiles.png, generated by tilext {}\n\n\
+            pub const ATLAS_WIDTH: u32 = 8;\npub const ATLAS_HEIGHT: u32 = 3;\n\
+            pub const TILE_WIDTH: u32 = 2;\npub const TILE_HEIGHT: u32 = 1;\n\
+            pub const GUTTER: u32 = 1;\n\
+            pub const COLUMNS: u32 = 2;\npub const ROWS: u32 = 1;\n\
+            pub const TILE_COUNT: usize = 2;\n\
+            \n/// Tiles without their gutters, as [x, y, width, height] in pixels\n\
+            pub const TILE_RECTS: [[u32; 4]; 2] = [\n    [1, 1, 2, 1],\n    [5, 1, 2, 1],\n];\n\
+            \n/// Tiles as [u0, v0, u1, v1]\n\
+            pub const TILE_UVS: [[f32; 4]; 2] = [\n    [0.125, 0.33333334, 0.375, 0.6666667],\n    [0.625, 0.33333334, 0.875, 0.6666667],\n];\n\
+            \n/// Indices of named tiles\npub mod tiles {{\n    pub const _1ST_WATER: usize = 1;\n}}\n",
+            env!("CARGO_PKG_VERSION")));
+
+        // Without names there's no tiles module
+        let rust = atlas.to_rust("tiles.png", &names(&[" ", ""]), true).unwrap();
+        assert!(!rust.contains("mod tiles"));
+        assert!(rust.contains("/// Tiles as [u0, v0, u1, v1], half a pixel inside their edges\n"));
+
+        let bad = |tile_names: &[&str]| match atlas.to_rust("tiles.png", &names(tile_names), false)
+        {
+            Err(Error::BadArguments(message)) => message,
+            result => panic!("{:?}", result)
+        };
+
+        assert_eq!(bad(&["grass top", "Grass-Top"]), "Several tiles are named GRASS_TOP");
+        assert_eq!(bad(&["a", "b", "c"]), "3 tile names given for 2 tiles");
+        assert_eq!(bad(&["grass", "--"]), "Tile name \"--\" has no letters or digits for a constant name");
+    }
+}
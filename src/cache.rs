@@ -0,0 +1,196 @@
+//! Cache of processed files for --incremental, so files whose input, settings
+//! and output haven't changed since the last run are skipped
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tilext::Error;
+
+const HEADER: &str = "# tilext cache v1";
+
+/// Hashes of one processed file
+#[derive(Debug, Clone, PartialEq)]
+struct Entry
+{
+    input: u64,
+    config: u64,
+    output: u64
+}
+
+pub struct Cache
+{
+    path: PathBuf,
+    entries: HashMap<String, Entry>
+}
+
+/// FNV-1a, which (unlike the std hasher) is stable between Rust versions
+pub fn hash(bytes: &[u8]) -> u64
+{
+    let mut hash = 0xcbf29ce484222325u64;
+    for &b in bytes
+    {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    return hash;
+}
+
+fn key(input_path: &Path) -> String
+{
+    input_path.to_string_lossy().into_owned()
+}
+
+fn hash_file(path: &Path) -> Option<u64>
+{
+    fs::read(path).ok().map(|bytes| hash(&bytes))
+}
+
+impl Cache
+{
+    /// Loads the cache file, or starts an empty cache if there's none yet.
+    /// Lines that can't be parsed are dropped.
+    pub fn load(path: &Path) -> Result<Cache, Error>
+    {
+        let mut entries = HashMap::new();
+
+        if path.exists()
+        {
+            let text = fs::read_to_string(path).map_err(|e| Error::BadArguments(format!("{} ({:?})", e, path)))?;
+
+            for line in text.lines().filter(|l| !l.starts_with('#'))
+            {
+                let mut fields = line.splitn(4, ' ');
+                let mut next_hash = || fields.next().and_then(|f| u64::from_str_radix(f, 16).ok());
+
+                if let (Some(input), Some(config), Some(output)) = (next_hash(), next_hash(), next_hash())
+                {
+                    if let Some(input_path) = fields.next()
+                    {
+                        entries.insert(input_path.to_string(), Entry { input, config, output });
+                    }
+                }
+            }
+        }
+
+        return Ok(Cache { path: path.to_path_buf(), entries });
+    }
+
+    /// Whether the output was written from this exact input and config, and
+    /// hasn't been touched since
+    pub fn is_up_to_date(&self, input_path: &Path, input_hash: u64, config_hash: u64, output_path: &Path) -> bool
+    {
+        match self.entries.get(&key(input_path))
+        {
+            Some(entry) => entry.input == input_hash && entry.config == config_hash && hash_file(output_path) == Some(entry.output),
+            None => false
+        }
+    }
+
+    /// Remembers a written output. When the output replaced the input, the
+    /// new input is the output, so that's what is recorded for it.
+    pub fn record(&mut self, input_path: &Path, input_hash: u64, config_hash: u64, output_path: &Path, in_place: bool)
+    {
+        match hash_file(output_path)
+        {
+            Some(output) =>
+            {
+                let input = if in_place { output } else { input_hash };
+                self.entries.insert(key(input_path), Entry { input, config: config_hash, output });
+            },
+            None =>
+            {
+                self.entries.remove(&key(input_path));
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Error>
+    {
+        let mut keys: Vec<&String> = self.entries.keys().collect();
+        keys.sort();
+
+        let mut text = String::from(HEADER);
+        text.push('\n');
+        for key in keys
+        {
+            let entry = &self.entries[key];
+            text.push_str(&format!("{:016x} {:016x} {:016x} {}\n", entry.input, entry.config, entry.output, key));
+        }
+
+        return tilext::write_atomic(&self.path, text.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn entries_survive_save_and_load()
+    {
+        let dir = ::test_dir("cache");
+        let cache_path = dir.join(".tilext-cache");
+        let (input_path, output_path) = (dir.join("my tiles.png"), dir.join("my tiles_x.png"));
+        fs::write(&output_path, b"output").unwrap();
+
+        let mut cache = Cache::load(&cache_path).unwrap();
+        assert!(!cache.is_up_to_date(&input_path, 1, 2, &output_path));
+
+        cache.record(&input_path, 1, 2, &output_path, false);
+        cache.save().unwrap();
+
+        // Lines that can't be parsed are dropped
+        let mut text = fs::read_to_string(&cache_path).unwrap();
+        assert!(text.starts_with(HEADER));
+        text.push_str("not a cache line\n");
+        fs::write(&cache_path, text).unwrap();
+
+        let cache = Cache::load(&cache_path).unwrap();
+        assert_eq!(cache.entries.len(), 1);
+        assert!(cache.is_up_to_date(&input_path, 1, 2, &output_path));
+        assert!(!cache.is_up_to_date(&input_path, 3, 2, &output_path));
+        assert!(!cache.is_up_to_date(&input_path, 1, 3, &output_path));
+        assert!(!cache.is_up_to_date(&dir.join("other.png"), 1, 2, &output_path));
+
+        // Touching the output makes it out of date, as does removing it
+        fs::write(&output_path, b"edited").unwrap();
+        assert!(!cache.is_up_to_date(&input_path, 1, 2, &output_path));
+        fs::remove_file(&output_path).unwrap();
+        assert!(!cache.is_up_to_date(&input_path, 1, 2, &output_path));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn in_place_outputs_are_up_to_date_on_the_next_run()
+    {
+        let dir = ::test_dir("cache-in-place");
+        let path = dir.join("a.png");
+        fs::write(&path, b"original").unwrap();
+
+        // First run: the output replaces the input
+        let mut cache = Cache::load(&dir.join(".tilext-cache")).unwrap();
+        let first_input = hash(&fs::read(&path).unwrap());
+        assert!(!cache.is_up_to_date(&path, first_input, 7, &path));
+
+        fs::write(&path, b"extruded").unwrap();
+        cache.record(&path, first_input, 7, &path, true);
+        cache.save().unwrap();
+
+        // Next run: the input is what the first run wrote
+        let cache = Cache::load(&dir.join(".tilext-cache")).unwrap();
+        let second_input = hash(&fs::read(&path).unwrap());
+        assert!(cache.is_up_to_date(&path, second_input, 7, &path));
+        assert!(!cache.is_up_to_date(&path, second_input, 8, &path));
+
+        // Recording a missing output forgets the file
+        let mut cache = cache;
+        cache.record(&path, second_input, 7, &dir.join("missing.png"), false);
+        assert!(!cache.is_up_to_date(&path, second_input, 7, &path));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
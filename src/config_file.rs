@@ -0,0 +1,154 @@
+//! The tilext.toml project file. Its keys are the long names of the command
+//! line options, and [[override]] tables change some of them for the files
+//! matching a pattern:
+//!
+//! ```toml
+//! inputs = ["art"]
+//! recursive = true
+//! tile-size = 16
+//! output-dir = "target/assets"
+//!
+//! [[override]]
+//! pattern = "art/characters/*.png"
+//! tile-size = 32
+//! extrude-mode = "mirror"
+//! ```
+//!
+//! Relative paths and patterns in the file are relative to the file's folder,
+//! not the working directory.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+use toml::{Table, Value};
+
+use tilext::Error;
+
+/// Project file looked for in the working directory when --config isn't given
+pub const DEFAULT_PATH: &str = "tilext.toml";
+
+/// Options whose values are paths
+const PATH_KEYS: &[&str] = &["output-dir", "update-tiled", "tile-names", "cache-file"];
+
+/// Options whose values are patterns
+const PATTERN_KEYS: &[&str] = &["exclude"];
+
+/// Settings of a project file, as option names and values. Options without
+/// a value are "true" or "false".
+#[derive(Debug, Default)]
+pub struct ConfigFile
+{
+    pub path: PathBuf,
+    pub settings: Vec<(String, String)>,
+    /// Settings for the files matching a pattern, in file order
+    pub overrides: Vec<(Pattern, Vec<(String, String)>)>,
+    /// Inputs used when none are given on the command line
+    pub inputs: Vec<String>
+}
+
+fn value_strings(key: &str, value: &Value) -> Result<Vec<String>, String>
+{
+    let string = match *value
+    {
+        Value::String(ref s) => s.clone(),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Array(ref values) =>
+        {
+            let mut strings = Vec::new();
+            for value in values
+            {
+                strings.extend(value_strings(key, value)?);
+            }
+            return Ok(strings);
+        },
+        _ => return Err(format!("Unexpected value for {}", key))
+    };
+
+    return Ok(vec![string]);
+}
+
+/// A relative path of the file, relative to the working directory instead
+fn resolve_path(dir: &Path, path: String) -> String
+{
+    if dir.as_os_str().is_empty()
+    {
+        return path;
+    }
+
+    return dir.join(path).to_string_lossy().into_owned();
+}
+
+/// A relative pattern of the file, relative to the working directory instead
+fn resolve_pattern(dir: &Path, pattern: String) -> String
+{
+    if dir.as_os_str().is_empty() || Path::new(&pattern).is_absolute()
+    {
+        return pattern;
+    }
+
+    return format!("{}/{}", Pattern::escape(&dir.to_string_lossy()), pattern);
+}
+
+fn table_settings(table: &Table, skip: &[&str], dir: &Path) -> Result<Vec<(String, String)>, String>
+{
+    let mut settings = Vec::new();
+
+    for (key, value) in table.iter().filter(|&(key, _)| !skip.contains(&key.as_str()))
+    {
+        for string in value_strings(key, value)?
+        {
+            let string = match key.as_str()
+            {
+                key if PATH_KEYS.contains(&key) => resolve_path(dir, string),
+                key if PATTERN_KEYS.contains(&key) => resolve_pattern(dir, string),
+                _ => string
+            };
+            settings.push((key.clone(), string));
+        }
+    }
+
+    return Ok(settings);
+}
+
+impl ConfigFile
+{
+    pub fn load(path: &Path) -> Result<ConfigFile, Error>
+    {
+        let in_file = |message: String| Error::BadArguments(format!("{} (in {:?})", message, path));
+
+        let text = fs::read_to_string(path).map_err(|e| in_file(e.to_string()))?;
+        let table: Table = text.parse().map_err(|e: toml::de::Error| in_file(e.message().to_string()))?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let settings = table_settings(&table, &["inputs", "override"], dir).map_err(in_file)?;
+
+        let inputs = match table.get("inputs")
+        {
+            Some(value) => value_strings("inputs", value).map_err(in_file)?.into_iter().map(|input| resolve_path(dir, input)).collect(),
+            None => Vec::new()
+        };
+
+        let mut overrides = Vec::new();
+        if let Some(value) = table.get("override")
+        {
+            let tables = value.as_array().ok_or_else(|| in_file("Expected [[override]] tables".into()))?;
+
+            for table in tables
+            {
+                let table = table.as_table().ok_or_else(|| in_file("Expected [[override]] tables".into()))?;
+
+                let pattern = table.get("pattern").and_then(Value::as_str).ok_or_else(
+                    || in_file("Override without a pattern".into())
+                )?;
+                let pattern = Pattern::new(&resolve_pattern(dir, pattern.into())).map_err(|e| in_file(format!("{} (in pattern {})", e, pattern)))?;
+
+                overrides.push((pattern, table_settings(table, &["pattern"], dir).map_err(in_file)?));
+            }
+        }
+
+        return Ok(ConfigFile { path: path.to_path_buf(), settings, overrides, inputs });
+    }
+}
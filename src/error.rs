@@ -0,0 +1,36 @@
+use std::error;
+use std::fmt;
+
+/// Everything that can go wrong while extruding a tileset
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error
+{
+    /// Invalid command line arguments or options
+    BadArguments(String),
+    /// The input image couldn't be read or decoded
+    Decode(String),
+    /// The image size doesn't fit the tile layout
+    BadDimensions(String),
+    /// The output couldn't be encoded or written
+    Write(String),
+    /// A Tiled map or tileset, or a Godot TileSet resource, couldn't be read
+    /// or updated
+    Tileset(String)
+}
+
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            Error::BadArguments(ref s) => write!(f, "Bad arguments: {}", s),
+            Error::Decode(ref s) => write!(f, "Couldn't decode image: {}", s),
+            Error::BadDimensions(ref s) => write!(f, "Bad image dimensions: {}", s),
+            Error::Write(ref s) => write!(f, "Couldn't write image: {}", s),
+            Error::Tileset(ref s) => write!(f, "Couldn't update tileset: {}", s)
+        }
+    }
+}
+
+impl error::Error for Error {}
@@ -0,0 +1,314 @@
+//! Godot 4 TileSet resources (.tres) describing extruded or stripped images
+
+use Error;
+use atlas::Atlas;
+
+/// A `TileSetAtlasSource` for an image, as Godot lays out its tiles
+#[derive(Debug, Clone, PartialEq)]
+pub struct AtlasSource
+{
+    /// Path of the image as the resource refers to it, usually res://...
+    pub texture_path: String,
+    pub tile_width: usize,
+    pub tile_height: usize,
+    /// Offset of the first tile from the top left of the image
+    pub margin: usize,
+    /// Gap between neighbouring tiles
+    pub separation: usize,
+    pub columns: usize,
+    pub rows: usize
+}
+
+/// Escapes text for a string in a Godot resource, with the quotes
+fn quote(s: &str) -> String
+{
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Value of an attribute in a section header such as
+/// `[ext_resource type="Texture2D" path="res://a.png" id="1_abc"]`, without
+/// quotes
+fn header_attribute<'a>(header: &'a str, name: &str) -> Option<&'a str>
+{
+    let pattern = format!(" {}=", name);
+    let start = header.find(&pattern)? + pattern.len();
+    let rest = &header[start..];
+
+    if let Some(quoted) = rest.strip_prefix('"')
+    {
+        return quoted.find('"').map(|end| &quoted[..end]);
+    }
+
+    let end = rest.find([' ', ']']).unwrap_or(rest.len());
+    return Some(&rest[..end]);
+}
+
+/// Resource id that a property value such as `ExtResource("1_abc")` or
+/// `ExtResource( 1 )` refers to
+fn ext_resource_id(value: &str) -> Option<&str>
+{
+    let inner = value.trim().strip_prefix("ExtResource(")?.strip_suffix(')')?.trim();
+    return Some(inner.trim_matches('"'));
+}
+
+fn vector(x: usize, y: usize) -> String
+{
+    format!("Vector2i({}, {})", x, y)
+}
+
+impl AtlasSource
+{
+    /// The atlas source of an output image, whose texture is at `texture_path`
+    pub fn new(texture_path: &str, atlas: &Atlas) -> AtlasSource
+    {
+        AtlasSource
+        {
+            texture_path: texture_path.into(),
+            tile_width: atlas.tile_width,
+            tile_height: atlas.tile_height,
+            // Like Tiled, Godot counts the margin once at the edge and the
+            // separation once between tiles
+            margin: atlas.gutter,
+            separation: atlas.gutter * 2,
+            columns: atlas.columns,
+            rows: atlas.rows
+        }
+    }
+
+    /// Property lines of the layout, in the order Godot writes them
+    fn layout_properties(&self) -> Vec<(&'static str, String)>
+    {
+        vec![
+            ("margins", vector(self.margin, self.margin)),
+            ("separation", vector(self.separation, self.separation)),
+            ("texture_region_size", vector(self.tile_width, self.tile_height))]
+    }
+
+    /// Atlas coordinates of every tile, as the keys of their property lines
+    fn tile_keys(&self) -> Vec<String>
+    {
+        let mut keys = Vec::with_capacity(self.columns * self.rows);
+        for row in 0..self.rows
+        {
+            for column in 0..self.columns
+            {
+                keys.push(format!("{}:{}/0", column, row));
+            }
+        }
+
+        return keys;
+    }
+
+    /// A new TileSet resource with this atlas as its only source
+    pub fn to_tres(&self) -> String
+    {
+        let mut tres = String::from("[gd_resource type=\"TileSet\" load_steps=3 format=3]\n\n");
+        tres.push_str(&format!("[ext_resource type=\"Texture2D\" path={} id=\"1_tilext\"]\n\n", quote(&self.texture_path)));
+
+        tres.push_str("[sub_resource type=\"TileSetAtlasSource\" id=\"TileSetAtlasSource_tilext\"]\n");
+        tres.push_str("texture = ExtResource(\"1_tilext\")\n");
+        for (name, value) in self.layout_properties()
+        {
+            tres.push_str(&format!("{} = {}\n", name, value));
+        }
+        for key in self.tile_keys()
+        {
+            tres.push_str(&format!("{} = 0\n", key));
+        }
+
+        tres.push_str("\n[resource]\n");
+        tres.push_str(&format!("tile_size = {}\n", vector(self.tile_width, self.tile_height)));
+        tres.push_str("sources/0 = SubResource(\"TileSetAtlasSource_tilext\")\n");
+        return tres;
+    }
+
+    /// Updates the atlas source using this texture in an existing TileSet
+    /// resource: its margins, separation and region size are set, and tiles
+    /// it lacks are added. Everything else (physics, terrains, custom data,
+    /// other sources) is kept as it is.
+    pub fn merge_tres(&self, tres: &str) -> Result<String, Error>
+    {
+        let bad_tres = Error::Tileset;
+
+        let mut lines: Vec<String> = tres.lines().map(String::from).collect();
+        let is_header = |line: &str| line.starts_with('[');
+
+        //
+        // Find the texture, by path or else by file name
+        //
+
+        let textures: Vec<(&str, &str)> = lines.iter()
+            .filter(|line| line.starts_with("[ext_resource "))
+            .filter_map(|line| Some((header_attribute(line, "path")?, header_attribute(line, "id")?)))
+            .collect();
+
+        let file_name = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+        let by_name: Vec<&(&str, &str)> = textures.iter().filter(|&&(path, _)| file_name(path) == file_name(&self.texture_path)).collect();
+
+        let texture_id = match textures.iter().find(|&&(path, _)| path == self.texture_path)
+        {
+            Some(&(_, id)) => id.to_string(),
+            None if by_name.len() == 1 => by_name[0].1.to_string(),
+            None => return Err(bad_tres(format!("no texture {}", self.texture_path)))
+        };
+
+        //
+        // Find the atlas source using it
+        //
+
+        let mut section = None;
+        let is_atlas_source = |line: &str| line.starts_with("[sub_resource ") && header_attribute(line, "type") == Some("TileSetAtlasSource");
+
+        for i in (0..lines.len()).filter(|&i| is_atlas_source(&lines[i]))
+        {
+            let end = lines[i + 1..].iter().position(|l| is_header(l)).map_or(lines.len(), |n| i + 1 + n);
+            let uses_texture = lines[i + 1..end].iter()
+                .filter_map(|l| l.strip_prefix("texture = "))
+                .any(|value| ext_resource_id(value) == Some(texture_id.as_str()));
+
+            if uses_texture
+            {
+                section = Some((i + 1, end));
+                break;
+            }
+        }
+
+        let (start, mut end) = section.ok_or_else(|| bad_tres(format!("no atlas source uses {}", self.texture_path)))?;
+
+        // Leave the blank lines before the next section where they are
+        while end > start && lines[end - 1].trim().is_empty()
+        {
+            end -= 1;
+        }
+
+        //
+        // Set the layout after the texture, and add missing tiles at the end
+        //
+
+        let texture_line = (start..end).find(|&i| lines[i].starts_with("texture = ")).unwrap_or(start);
+        let mut insert_at = texture_line + 1;
+
+        for (name, value) in self.layout_properties()
+        {
+            let prefix = format!("{} = ", name);
+            let line = format!("{}{}", prefix, value);
+
+            match (start..end).find(|&i| lines[i].starts_with(&prefix))
+            {
+                Some(i) => lines[i] = line,
+
+                None =>
+                {
+                    lines.insert(insert_at, line);
+                    insert_at += 1;
+                    end += 1;
+                }
+            }
+        }
+
+        let missing: Vec<String> = self.tile_keys().into_iter()
+            .filter(|key| !lines[start..end].iter().any(|l| l.starts_with(&format!("{} ", key)) || l.starts_with(&format!("{}/", key))))
+            .collect();
+
+        for (n, key) in missing.iter().enumerate()
+        {
+            lines.insert(end + n, format!("{} = 0", key));
+        }
+
+        let mut merged = lines.join("\n");
+        merged.push('\n');
+        return Ok(merged);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use Options;
+
+    /// 2x2 tiles of 4x4 pixels, extruded into gutters of 1
+    fn extruded(texture_path: &str) -> AtlasSource
+    {
+        let mut options = Options::new(4, 4);
+        options.gutter = 1;
+        AtlasSource::new(texture_path, &Atlas::extruded(12, 12, &options))
+    }
+
+    fn stripped(texture_path: &str) -> AtlasSource
+    {
+        AtlasSource::new(texture_path, &Atlas::stripped(8, 8, &Options::new(4, 4)))
+    }
+
+    #[test]
+    fn merge_keeps_tile_data()
+    {
+        let tres = stripped("res://tiles.png").to_tres()
+            .replace("0:0/0 = 0\n", "0:0/0 = 0\n0:0/0/physics_layer_0/polygon_0/points = PackedVector2Array(-2, -2, 2, -2, 2, 2)\n")
+            .replace("1:1/0 = 0\n", "")
+            .replace("[resource]\n", "[resource]\nphysics_layer_0/collision_layer = 1\n");
+
+        let merged = extruded("res://tiles.png").merge_tres(&tres).unwrap();
+        assert_eq!(merged, extruded("res://tiles.png").to_tres()
+            .replace("0:0/0 = 0\n", "0:0/0 = 0\n0:0/0/physics_layer_0/polygon_0/points = PackedVector2Array(-2, -2, 2, -2, 2, 2)\n")
+            .replace("[resource]\n", "[resource]\nphysics_layer_0/collision_layer = 1\n"));
+
+        // Merging again changes nothing
+        assert_eq!(extruded("res://tiles.png").merge_tres(&merged).unwrap(), merged);
+    }
+
+    #[test]
+    fn merge_adds_missing_layout()
+    {
+        let tres = "[gd_resource type=\"TileSet\" load_steps=3 format=3 uid=\"uid://abc\"]\n\
+            \n\
+            [ext_resource type=\"Texture2D\" uid=\"uid://def\" path=\"res://art/tiles.png\" id=\"1_x2k\"]\n\
+            \n\
+            [sub_resource type=\"TileSetAtlasSource\" id=\"TileSetAtlasSource_7qd\"]\n\
+            texture = ExtResource(\"1_x2k\")\n\
+            0:0/0 = 0\n\
+            1:0/0 = 0\n\
+            0:1/0 = 0\n\
+            1:1/0 = 0\n\
+            \n\
+            [resource]\n\
+            sources/0 = SubResource(\"TileSetAtlasSource_7qd\")\n";
+
+        assert_eq!(extruded("res://art/tiles.png").merge_tres(tres).unwrap(), tres.replace(
+            "texture = ExtResource(\"1_x2k\")\n",
+            "texture = ExtResource(\"1_x2k\")\n\
+            margins = Vector2i(1, 1)\n\
+            separation = Vector2i(2, 2)\n\
+            texture_region_size = Vector2i(4, 4)\n"));
+    }
+
+    #[test]
+    fn merge_finds_the_texture_by_file_name()
+    {
+        let tres = stripped("res://art/tiles.png").to_tres();
+        let expected = extruded("res://art/tiles.png").to_tres();
+
+        // The resource keeps its own path to the texture
+        assert_eq!(extruded("../art/tiles.png").merge_tres(&tres).unwrap(), expected);
+
+        match extruded("res://art/grass.png").merge_tres(&tres)
+        {
+            Err(Error::Tileset(message)) => assert_eq!(message, "no texture res://art/grass.png"),
+            result => panic!("{:?}", result)
+        }
+
+        // Two textures of that name and neither path matching is ambiguous
+        let two = tres.replace("[ext_resource ", "[ext_resource type=\"Texture2D\" path=\"res://other/tiles.png\" id=\"2_tilext\"]\n[ext_resource ");
+        assert!(extruded("../art/tiles.png").merge_tres(&two).is_err());
+        assert_eq!(extruded("res://art/tiles.png").merge_tres(&two).unwrap(), expected.replace(
+            "[ext_resource ", "[ext_resource type=\"Texture2D\" path=\"res://other/tiles.png\" id=\"2_tilext\"]\n[ext_resource "));
+
+        // A texture no atlas source uses
+        let unused = tres.replace("texture = ExtResource(\"1_tilext\")\n", "");
+        match extruded("res://art/tiles.png").merge_tres(&unused)
+        {
+            Err(Error::Tileset(message)) => assert_eq!(message, "no atlas source uses res://art/tiles.png"),
+            result => panic!("{:?}", result)
+        }
+    }
+}
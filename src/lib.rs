@@ -0,0 +1,903 @@
+//! Extrudes the tiles of a tileset into gutters, so that texture filtering
+//! doesn't bleed neighbouring tiles into each other.
+//!
+//! [`extrude`] and [`strip`] work on in-memory RGBA buffers, and
+//! [`extrude_pixels`] and [`strip_pixels`] on buffers of any pixel type. The
+//! [`png`] module reads and writes PNG files in their own color type and bit
+//! depth, [`tiled`] and [`godot`] describe the results as Tiled tilesets and
+//! Godot TileSets, and [`atlas`] lists where each tile ended up.
+
+#![allow(clippy::needless_return)]
+
+extern crate lodepng;
+extern crate rgb;
+
+use std::fs;
+use std::path::Path;
+
+pub mod atlas;
+mod error;
+pub mod godot;
+pub mod png;
+pub mod tiled;
+
+pub use lodepng::RGBA;
+pub use error::Error;
+
+/// What gutter pixels are filled with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtrudeMode
+{
+    /// Repeat the nearest edge pixel
+    Clamp,
+    /// Repeat the opposite edge of the tile, as with repeating UVs
+    Wrap,
+    /// Mirror the tile at its edges
+    Mirror,
+    /// Fill with fully transparent pixels
+    Transparent,
+    /// Fill with a fixed color
+    Color(RGBA)
+}
+
+/// What to do with tiles that are cut off by the edge of the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartialTiles
+{
+    /// Fail with an error
+    Error,
+    /// Drop the partial tiles
+    Crop,
+    /// Complete the partial tiles with transparent pixels
+    Pad
+}
+
+/// Layout of the input tileset and how to extrude it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options
+{
+    pub tile_width: usize,
+    pub tile_height: usize,
+    pub gutter: usize,
+    pub extrude_mode: ExtrudeMode,
+    pub input_margin: usize,
+    pub input_spacing: usize,
+    pub partial_tiles: PartialTiles
+}
+
+impl Options
+{
+    /// Options for tiles of the given size, with 1-pixel clamped gutters and
+    /// an input without margin or spacing
+    pub fn new(tile_width: usize, tile_height: usize) -> Options
+    {
+        Options
+        {
+            tile_width,
+            tile_height,
+            gutter: 1,
+            extrude_mode: ExtrudeMode::Clamp,
+            input_margin: 0,
+            input_spacing: 0,
+            partial_tiles: PartialTiles::Error
+        }
+    }
+}
+
+/// Values for pixels that aren't copied from a tile: gutters in the
+/// transparent and color extrude modes, and the padding of partial tiles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fill<P>
+{
+    /// Used by [`ExtrudeMode::Transparent`] and for padding
+    pub transparent: P,
+    /// Used by [`ExtrudeMode::Color`]
+    pub color: P
+}
+
+impl Fill<RGBA>
+{
+    pub fn rgba(mode: ExtrudeMode) -> Fill<RGBA>
+    {
+        let transparent = RGBA { r: 0, g: 0, b: 0, a: 0 };
+
+        Fill
+        {
+            transparent,
+            color: match mode
+            {
+                ExtrudeMode::Color(color) => color,
+                _ => transparent
+            }
+        }
+    }
+}
+
+/// An image, stored row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image<P = RGBA>
+{
+    pub buffer: Vec<P>,
+    pub width: usize,
+    pub height: usize
+}
+
+/// Result of [`extrude`]
+#[derive(Debug, Clone)]
+pub struct Extruded<I = Image>
+{
+    pub image: I,
+    pub columns: usize,
+    pub rows: usize,
+    /// Whether partial tiles were cropped or padded
+    pub had_partial_tiles: bool
+}
+
+/// Result of [`strip`]
+#[derive(Debug, Clone)]
+pub struct Stripped<I = Image>
+{
+    pub image: I,
+    pub columns: usize,
+    pub rows: usize,
+    /// Indices of tiles whose gutters don't match what extrusion would have produced
+    pub mismatched_tiles: Vec<usize>
+}
+
+fn get_pixel_index(pixel_x: usize, pixel_y: usize, image_width: usize) -> usize
+{
+    pixel_y * image_width + pixel_x
+}
+
+/// Maps a pixel position within a tile and its gutters (along one axis) to the
+/// position within the tile proper that it is extruded from
+fn map_to_tile(tile_pixel: usize, gutter: usize, tile_size: usize, mode: ExtrudeMode) -> usize
+{
+    let offset = tile_pixel as isize - gutter as isize;
+    let size = tile_size as isize;
+
+    let inner = match mode
+    {
+        ExtrudeMode::Wrap => offset.rem_euclid(size),
+        ExtrudeMode::Mirror =>
+        {
+            // Mirrored repeat, where the edge pixel is repeated once at the boundary
+            let m = offset.rem_euclid(size * 2);
+            if m < size { m } else { size * 2 - 1 - m }
+        },
+        _ => offset.max(0).min(size - 1)
+    };
+
+    return inner as usize;
+}
+
+/// The value a gutter pixel gets, given the tile pixel it is extruded from
+fn gutter_pixel<P: Copy>(mode: ExtrudeMode, source: P, fill: &Fill<P>) -> P
+{
+    match mode
+    {
+        ExtrudeMode::Transparent => fill.transparent,
+        ExtrudeMode::Color(_) => fill.color,
+        _ => source
+    }
+}
+
+/// Counts how many whole tiles fit along one axis of an image laid out with
+/// an outer margin and spacing between tiles (as in Tiled)
+fn count_tiles(image_size: usize, tile_size: usize, margin: usize, spacing: usize) -> usize
+{
+    let first_tile_end = match margin.checked_mul(2).and_then(|m| m.checked_add(tile_size))
+    {
+        Some(end) if end <= image_size => end,
+        _ => return 0
+    };
+
+    return (image_size - first_tile_end) / tile_size.saturating_add(spacing) + 1;
+}
+
+/// Whether there are pixels left over after the last whole tile along one
+/// axis, including an image too small (or with too large a margin) to hold
+/// any whole tile
+fn has_partial_tile(image_size: usize, tile_size: usize, margin: usize, spacing: usize) -> bool
+{
+    let count = count_tiles(image_size, tile_size, margin, spacing);
+    if count == 0
+    {
+        return image_size > 0;
+    }
+
+    return image_size > (margin * 2).saturating_add(count.saturating_mul(tile_size.saturating_add(spacing)));
+}
+
+/// Position of a tile along one axis of the input. Padded tiles past a huge
+/// margin or spacing saturate, which reads as past the end of the image.
+fn tile_start(index: usize, tile_size: usize, margin: usize, spacing: usize) -> usize
+{
+    margin.saturating_add(index.saturating_mul(tile_size.saturating_add(spacing)))
+}
+
+/// Reads one pixel row of a tile, whose top-left pixel is at `src` in the
+/// input. Pixels at or past `max` (the end of the image, or the start of its
+/// far margin) are `blank`, which pads partial tiles.
+fn read_tile_row<P: Copy>(buffer: &[P], width: usize, max: (usize, usize), src: (usize, usize), row: &mut [P], blank: P)
+{
+    let (max_x, max_y) = max;
+    let (src_x, src_y) = src;
+
+    if src_y < max_y && src_x.saturating_add(row.len()) <= max_x
+    {
+        let from_i = get_pixel_index(src_x, src_y, width);
+        row.copy_from_slice(&buffer[from_i..from_i + row.len()]);
+    }
+    else
+    {
+        for (i, pixel) in row.iter_mut().enumerate()
+        {
+            *pixel = if src_x.saturating_add(i) < max_x && src_y < max_y
+            {
+                buffer[get_pixel_index(src_x + i, src_y, width)]
+            }
+            else
+            {
+                blank
+            };
+        }
+    }
+}
+
+/// Size of a tile with a gutter on each side, or None if it overflows
+fn with_gutters(tile_size: usize, gutter: usize) -> Option<usize>
+{
+    gutter.checked_mul(2)?.checked_add(tile_size)
+}
+
+fn check_options(options: &Options) -> Result<(), Error>
+{
+    if options.tile_width == 0 || options.tile_height == 0
+    {
+        return Err(Error::BadArguments("Tile size must be greater than zero".into()));
+    }
+
+    if with_gutters(options.tile_width.max(options.tile_height), options.gutter).is_none()
+    {
+        return Err(Error::BadArguments(format!("Gutter of {} pixels is too large", options.gutter)));
+    }
+
+    return Ok(());
+}
+
+fn check_buffer<P>(buffer: &[P], width: usize, height: usize) -> Result<(), Error>
+{
+    if width.checked_mul(height) != Some(buffer.len())
+    {
+        return Err(Error::BadDimensions(format!("Buffer holds {} pixels, expected {}x{}", buffer.len(), width, height)));
+    }
+
+    return Ok(());
+}
+
+/// Extrudes each tile of an RGBA image (`width * height` pixels, row by row)
+/// into gutters of `options.gutter` pixels, returning the resized image
+pub fn extrude(buffer: &[RGBA], width: usize, height: usize, options: &Options) -> Result<Extruded, Error>
+{
+    return extrude_pixels(buffer, width, height, options, &Fill::rgba(options.extrude_mode));
+}
+
+/// Like [`extrude`], for any pixel type. Since there's no general way to turn
+/// the RGBA color of [`ExtrudeMode::Color`] (or transparency) into a pixel,
+/// those come from `fill`.
+pub fn extrude_pixels<P: Copy + PartialEq>(buffer: &[P], width: usize, height: usize, options: &Options, fill: &Fill<P>) -> Result<Extruded<Image<P>>, Error>
+{
+    check_options(options)?;
+    check_buffer(buffer, width, height)?;
+
+    //
+    // Find tiles in margin/spacing layout and handle partial tiles
+    //
+
+    let mut columns = count_tiles(width, options.tile_width, options.input_margin, options.input_spacing);
+    let mut rows = count_tiles(height, options.tile_height, options.input_margin, options.input_spacing);
+
+    let partial_column = has_partial_tile(width, options.tile_width, options.input_margin, options.input_spacing);
+    let partial_row = has_partial_tile(height, options.tile_height, options.input_margin, options.input_spacing);
+
+    if partial_column || partial_row
+    {
+        match options.partial_tiles
+        {
+            PartialTiles::Error =>
+            {
+                return Err(Error::BadDimensions(format!(
+                    "Image size {}x{} doesn't divide into whole {}x{} tiles (use --partial-tiles crop or pad)",
+                    width, height, options.tile_width, options.tile_height)));
+            },
+
+            PartialTiles::Crop => {},
+
+            PartialTiles::Pad =>
+            {
+                if partial_column { columns += 1; }
+                if partial_row { rows += 1; }
+            }
+        }
+    }
+
+    if columns == 0 || rows == 0
+    {
+        return Err(Error::BadDimensions(format!(
+            "Image size {}x{} holds no whole {}x{} tiles", width, height, options.tile_width, options.tile_height)));
+    }
+
+    //
+    // Copy each tile and its gutters into an image of the final size
+    //
+
+    let gutter = options.gutter;
+
+    // check_options made sure these don't overflow
+    let tile_width_with_gutters = options.tile_width + gutter * 2;
+    let tile_height_with_gutters = options.tile_height + gutter * 2;
+
+    let new_width = columns.checked_mul(tile_width_with_gutters);
+    let new_height = rows.checked_mul(tile_height_with_gutters);
+
+    let (new_width, new_height) = match (new_width, new_height)
+    {
+        (Some(w), Some(h)) if w.checked_mul(h).is_some() => (w, h),
+        _ => return Err(Error::BadDimensions(format!(
+            "Extruding {}x{} tiles of {}x{} pixels into {}-pixel gutters makes too large an image",
+            columns, rows, options.tile_width, options.tile_height, gutter)))
+    };
+
+    let mut output = Vec::new();
+    output.try_reserve_exact(new_width * new_height).map_err(|_| Error::BadDimensions(format!(
+        "Not enough memory for an extruded image of {}x{} pixels", new_width, new_height)))?;
+    let mut tile_row = vec![fill.transparent; options.tile_width];
+
+    let max = (width.saturating_sub(options.input_margin), height.saturating_sub(options.input_margin));
+
+    // Horizontal position within the tile proper that each output column is extruded from
+    let inner_xs: Vec<usize> = (0..tile_width_with_gutters)
+        .map(|tile_pixel_x| map_to_tile(tile_pixel_x, gutter, options.tile_width, options.extrude_mode))
+        .collect();
+
+    for tile_row_i in 0..rows
+    {
+        for tile_pixel_y in 0..tile_height_with_gutters
+        {
+            let inner_y = map_to_tile(tile_pixel_y, gutter, options.tile_height, options.extrude_mode);
+            let in_gutter_row = inner_y + gutter != tile_pixel_y;
+
+            let src_y = tile_start(tile_row_i, options.tile_height, options.input_margin, options.input_spacing).saturating_add(inner_y);
+
+            for tile_column_i in 0..columns
+            {
+                let src_x = tile_start(tile_column_i, options.tile_width, options.input_margin, options.input_spacing);
+                read_tile_row(buffer, width, max, (src_x, src_y), &mut tile_row, fill.transparent);
+
+                if in_gutter_row
+                {
+                    output.extend(inner_xs.iter().map(|&inner_x| gutter_pixel(options.extrude_mode, tile_row[inner_x], fill)));
+                }
+                else
+                {
+                    let right_gutter = &inner_xs[gutter + options.tile_width..];
+
+                    output.extend(inner_xs[..gutter].iter().map(|&inner_x| gutter_pixel(options.extrude_mode, tile_row[inner_x], fill)));
+                    output.extend_from_slice(&tile_row);
+                    output.extend(right_gutter.iter().map(|&inner_x| gutter_pixel(options.extrude_mode, tile_row[inner_x], fill)));
+                }
+            }
+        }
+    }
+
+    assert!(output.len() == new_width * new_height);
+
+    let image = Image { buffer: output, width: new_width, height: new_height };
+    return Ok(Extruded { image, columns, rows, had_partial_tiles: partial_column || partial_row });
+}
+
+/// Removes the gutters from an image produced by [`extrude`], rebuilding the
+/// packed tileset. Tiles whose gutters aren't what `options.extrude_mode`
+/// would have produced are reported in [`Stripped::mismatched_tiles`].
+///
+/// The input margin and spacing options are ignored, and since there is
+/// nothing sensible to pad with, [`PartialTiles::Pad`] crops like
+/// [`PartialTiles::Crop`].
+pub fn strip(buffer: &[RGBA], width: usize, height: usize, options: &Options) -> Result<Stripped, Error>
+{
+    return strip_pixels(buffer, width, height, options, &Fill::rgba(options.extrude_mode));
+}
+
+/// Like [`strip`], for any pixel type, with `fill` as for [`extrude_pixels`]
+pub fn strip_pixels<P: Copy + PartialEq>(buffer: &[P], width: usize, height: usize, options: &Options, fill: &Fill<P>) -> Result<Stripped<Image<P>>, Error>
+{
+    check_options(options)?;
+    check_buffer(buffer, width, height)?;
+
+    let gutter = options.gutter;
+
+    // check_options made sure these don't overflow
+    let tile_width_with_gutters = options.tile_width + gutter * 2;
+    let tile_height_with_gutters = options.tile_height + gutter * 2;
+
+    let columns = width / tile_width_with_gutters;
+    let rows = height / tile_height_with_gutters;
+
+    if (!width.is_multiple_of(tile_width_with_gutters) || !height.is_multiple_of(tile_height_with_gutters))
+        && options.partial_tiles == PartialTiles::Error
+    {
+        return Err(Error::BadDimensions(format!(
+            "Image size {}x{} doesn't divide into whole {}x{} tiles with gutters (use --partial-tiles crop)",
+            width, height, tile_width_with_gutters, tile_height_with_gutters)));
+    }
+
+    if columns == 0 || rows == 0
+    {
+        return Err(Error::BadDimensions(format!(
+            "Image size {}x{} holds no whole {}x{} tiles with gutters", width, height, tile_width_with_gutters, tile_height_with_gutters)));
+    }
+
+    let new_width = columns * options.tile_width;
+    let new_height = rows * options.tile_height;
+
+    let mut stripped = vec![fill.transparent; new_width * new_height];
+    let mut mismatched_tiles = Vec::new();
+
+    for tile_row_i in 0..rows
+    {
+        for tile_column_i in 0..columns
+        {
+            let tile_x = tile_column_i * tile_width_with_gutters;
+            let tile_y = tile_row_i * tile_height_with_gutters;
+            let mut gutters_match = true;
+
+            for tile_pixel_y in 0..tile_height_with_gutters
+            {
+                let inner_y = map_to_tile(tile_pixel_y, gutter, options.tile_height, options.extrude_mode);
+
+                for tile_pixel_x in 0..tile_width_with_gutters
+                {
+                    let inner_x = map_to_tile(tile_pixel_x, gutter, options.tile_width, options.extrude_mode);
+
+                    let from_i = get_pixel_index(tile_x + tile_pixel_x, tile_y + tile_pixel_y, width);
+
+                    if inner_x + gutter == tile_pixel_x && inner_y + gutter == tile_pixel_y
+                    {
+                        let to_i = get_pixel_index(
+                            tile_column_i * options.tile_width + inner_x,
+                            tile_row_i * options.tile_height + inner_y,
+                            new_width);
+                        stripped[to_i] = buffer[from_i];
+                    }
+                    else
+                    {
+                        // Gutter pixel, should match what extrusion would have put there
+                        let edge_i = get_pixel_index(tile_x + gutter + inner_x, tile_y + gutter + inner_y, width);
+                        gutters_match &= buffer[from_i] == gutter_pixel(options.extrude_mode, buffer[edge_i], fill);
+                    }
+                }
+            }
+
+            if !gutters_match
+            {
+                mismatched_tiles.push(tile_row_i * columns + tile_column_i);
+            }
+        }
+    }
+
+    let image = Image { buffer: stripped, width: new_width, height: new_height };
+    return Ok(Stripped { image, columns, rows, mismatched_tiles });
+}
+
+/// Writes a file through a temporary file next to it and a rename, so the
+/// file is never left half-written if the process dies
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), Error>
+{
+    let write_error = |e: std::io::Error| Error::Write(format!("{} ({:?})", e, path));
+
+    let name = path.file_name().ok_or_else(|| Error::Write(format!("Invalid path ({:?})", path)))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".tmp{}", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = fs::write(&temp_path, bytes).and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err()
+    {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    return result.map_err(write_error);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const MODES: &[ExtrudeMode] = &[
+        ExtrudeMode::Clamp,
+        ExtrudeMode::Wrap,
+        ExtrudeMode::Mirror,
+        ExtrudeMode::Transparent,
+        ExtrudeMode::Color(RGBA { r: 255, g: 0, b: 255, a: 255 })];
+
+    fn pixel(i: usize) -> RGBA
+    {
+        RGBA { r: i as u8, g: (i / 7) as u8, b: 100, a: 255 }
+    }
+
+    /// A `width * height` image where every pixel is different
+    fn sheet(width: usize, height: usize) -> Vec<RGBA>
+    {
+        (0..width * height).map(pixel).collect()
+    }
+
+    fn options(tile_width: usize, tile_height: usize, gutter: usize, mode: ExtrudeMode) -> Options
+    {
+        let mut options = Options::new(tile_width, tile_height);
+        options.gutter = gutter;
+        options.extrude_mode = mode;
+        return options;
+    }
+
+    #[test]
+    fn extrude_into_gutters_of_any_width()
+    {
+        // 2x2 tiles, 2 columns and 2 rows
+        let input = sheet(4, 4);
+
+        for gutter in 0..4
+        {
+            let extruded = extrude(&input, 4, 4, &options(2, 2, gutter, ExtrudeMode::Clamp)).unwrap();
+            let size = 2 * (2 + gutter * 2);
+            assert_eq!((extruded.image.width, extruded.image.height), (size, size));
+
+            // Every pixel repeats the nearest pixel of its own tile
+            for y in 0..size
+            {
+                for x in 0..size
+                {
+                    let inner = |p: usize| (p / (2 + gutter * 2)) * 2 + (p % (2 + gutter * 2)).saturating_sub(gutter).min(1);
+                    assert_eq!(extruded.image.buffer[y * size + x], input[inner(y) * 4 + inner(x)], "gutter {}, ({}, {})", gutter, x, y);
+                }
+            }
+        }
+
+        // Without gutters, the image is unchanged
+        let extruded = extrude(&input, 4, 4, &options(2, 2, 0, ExtrudeMode::Clamp)).unwrap();
+        assert_eq!(extruded.image.buffer, input);
+    }
+
+    #[test]
+    fn oversized_gutters_fail()
+    {
+        let input = sheet(4, 4);
+
+        for &gutter in &[usize::MAX, usize::MAX / 2]
+        {
+            let options = options(2, 2, gutter, ExtrudeMode::Clamp);
+            assert!(matches!(extrude(&input, 4, 4, &options), Err(Error::BadArguments(_))));
+            assert!(matches!(strip(&input, 4, 4, &options), Err(Error::BadArguments(_))));
+        }
+
+        // Each tile fits, but the whole image doesn't
+        let options = options(2, 2, usize::MAX / 8, ExtrudeMode::Clamp);
+        assert!(matches!(extrude(&input, 4, 4, &options), Err(Error::BadDimensions(_))));
+    }
+
+    #[test]
+    fn extrude_non_square_tiles()
+    {
+        let (a, b, c, d) = (pixel(1), pixel(2), pixel(3), pixel(4));
+
+        // Wide 2x1 tiles, 2 columns and 2 rows
+        let extruded = extrude(&[a, b, c, d], 2, 2, &options(2, 1, 1, ExtrudeMode::Clamp)).unwrap();
+        assert_eq!((extruded.columns, extruded.rows), (1, 2));
+        assert_eq!(extruded.image, Image { buffer: vec![
+            a, a, b, b,
+            a, a, b, b,
+            a, a, b, b,
+            c, c, d, d,
+            c, c, d, d,
+            c, c, d, d], width: 4, height: 6 });
+
+        // Tall 1x2 tiles
+        let extruded = extrude(&[a, b, c, d], 2, 2, &options(1, 2, 1, ExtrudeMode::Clamp)).unwrap();
+        assert_eq!((extruded.columns, extruded.rows), (2, 1));
+        assert_eq!(extruded.image, Image { buffer: vec![
+            a, a, a, b, b, b,
+            a, a, a, b, b, b,
+            c, c, c, d, d, d,
+            c, c, c, d, d, d], width: 6, height: 4 });
+    }
+
+    #[test]
+    fn extrude_skips_margin_and_spacing()
+    {
+        // 2x1 tiles in a margin of 1 and spacing of 2, marked x:
+        //   x x x x x x x x
+        //   x a b x x c d x
+        //   x x x x x x x x
+        let x = pixel(9);
+        let (a, b, c, d) = (pixel(1), pixel(2), pixel(3), pixel(4));
+        let input = [
+            x, x, x, x, x, x, x, x,
+            x, a, b, x, x, c, d, x,
+            x, x, x, x, x, x, x, x];
+
+        let mut options = options(2, 1, 1, ExtrudeMode::Clamp);
+        options.input_margin = 1;
+        options.input_spacing = 2;
+
+        let extruded = extrude(&input, 8, 3, &options).unwrap();
+        assert_eq!((extruded.columns, extruded.rows), (2, 1));
+        assert_eq!(extruded.image, Image { buffer: vec![
+            a, a, b, b, c, c, d, d,
+            a, a, b, b, c, c, d, d,
+            a, a, b, b, c, c, d, d], width: 8, height: 3 });
+    }
+
+    #[test]
+    fn strip_undoes_extrude_of_margin_and_spacing()
+    {
+        // 2 columns and 2 rows of 3x2 tiles, with a margin of 2 and spacing of 1
+        let (width, height) = (2 * 2 + 3 * 2 + 1, 2 * 2 + 2 * 2 + 1);
+        let input = sheet(width, height);
+
+        // The tiles packed edge to edge
+        let mut packed = Vec::new();
+        for y in (2..4).chain(5..7)
+        {
+            for x in (2..5).chain(6..9)
+            {
+                packed.push(input[y * width + x]);
+            }
+        }
+
+        for &mode in MODES
+        {
+            for &gutter in &[0, 1, 3]
+            {
+                let mut options = options(3, 2, gutter, mode);
+                options.input_margin = 2;
+                options.input_spacing = 1;
+
+                let extruded = extrude(&input, width, height, &options).unwrap();
+                assert_eq!((extruded.columns, extruded.rows, extruded.had_partial_tiles), (2, 2, false));
+
+                let stripped = strip(&extruded.image.buffer, extruded.image.width, extruded.image.height, &options).unwrap();
+                assert_eq!(stripped.image, Image { buffer: packed.clone(), width: 6, height: 4 }, "{:?}, gutter {}", mode, gutter);
+                assert!(stripped.mismatched_tiles.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn strip_undoes_extrude()
+    {
+        // 3x2 tiles, 4 columns and 3 rows
+        let input = sheet(12, 6);
+
+        for &mode in MODES
+        {
+            for &gutter in &[0, 1, 3]
+            {
+                let options = options(3, 2, gutter, mode);
+
+                let extruded = extrude(&input, 12, 6, &options).unwrap();
+                assert_eq!((extruded.columns, extruded.rows), (4, 3));
+                assert_eq!((extruded.image.width, extruded.image.height), (4 * (3 + gutter * 2), 3 * (2 + gutter * 2)));
+
+                let stripped = strip(&extruded.image.buffer, extruded.image.width, extruded.image.height, &options).unwrap();
+                assert_eq!(stripped.image, Image { buffer: input.clone(), width: 12, height: 6 }, "{:?}, gutter {}", mode, gutter);
+                assert!(stripped.mismatched_tiles.is_empty(), "{:?}, gutter {}", mode, gutter);
+            }
+        }
+    }
+
+    #[test]
+    fn strip_reports_mismatched_gutters()
+    {
+        let input = sheet(4, 4);
+        let options = options(2, 2, 1, ExtrudeMode::Clamp);
+        let mut extruded = extrude(&input, 4, 4, &options).unwrap().image;
+
+        // A gutter pixel of the second tile, and a pixel of the fourth tile proper
+        extruded.buffer[4] = pixel(200);
+        extruded.buffer[6 * 8 + 5] = pixel(201);
+
+        let stripped = strip(&extruded.buffer, 8, 8, &options).unwrap();
+        assert_eq!(stripped.mismatched_tiles, vec![1, 3]);
+        assert_eq!(stripped.image.buffer[3 * 4 + 2], pixel(201));
+
+        // Sizes that aren't whole tiles with gutters
+        assert!(matches!(strip(&extruded.buffer[..8 * 7], 8, 7, &options), Err(Error::BadDimensions(_))));
+
+        let mut crop = options.clone();
+        crop.partial_tiles = PartialTiles::Crop;
+        assert_eq!(strip(&extruded.buffer[..8 * 7], 8, 7, &crop).unwrap().rows, 1);
+    }
+
+    #[test]
+    fn extrude_fills_gutters()
+    {
+        // One 2x2 tile:
+        //   a b
+        //   c d
+        let (a, b, c, d) = (pixel(1), pixel(2), pixel(3), pixel(4));
+        let input = [a, b, c, d];
+
+        let expected = |mode: ExtrudeMode| -> Vec<RGBA>
+        {
+            match mode
+            {
+                ExtrudeMode::Clamp => vec![
+                    a, a, b, b,
+                    a, a, b, b,
+                    c, c, d, d,
+                    c, c, d, d],
+                ExtrudeMode::Wrap => vec![
+                    d, c, d, c,
+                    b, a, b, a,
+                    d, c, d, c,
+                    b, a, b, a],
+                ExtrudeMode::Mirror => vec![
+                    a, a, b, b,
+                    a, a, b, b,
+                    c, c, d, d,
+                    c, c, d, d],
+                ExtrudeMode::Transparent =>
+                {
+                    let t = RGBA { r: 0, g: 0, b: 0, a: 0 };
+                    vec![
+                        t, t, t, t,
+                        t, a, b, t,
+                        t, c, d, t,
+                        t, t, t, t]
+                },
+                ExtrudeMode::Color(k) => vec![
+                    k, k, k, k,
+                    k, a, b, k,
+                    k, c, d, k,
+                    k, k, k, k]
+            }
+        };
+
+        for &mode in MODES
+        {
+            let extruded = extrude(&input, 2, 2, &options(2, 2, 1, mode)).unwrap();
+            assert_eq!(extruded.image, Image { buffer: expected(mode), width: 4, height: 4 }, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn extrude_mirrors_wide_gutters()
+    {
+        // One 3x1 tile (a b c) in 2-pixel gutters, along the row
+        let (a, b, c) = (pixel(1), pixel(2), pixel(3));
+        let extruded = extrude(&[a, b, c], 3, 1, &options(3, 1, 2, ExtrudeMode::Mirror)).unwrap();
+
+        let middle_row = &extruded.image.buffer[2 * 7..3 * 7];
+        assert_eq!(middle_row, &[b, a, a, b, c, c, b]);
+
+        let wrapped = extrude(&[a, b, c], 3, 1, &options(3, 1, 2, ExtrudeMode::Wrap)).unwrap();
+        assert_eq!(&wrapped.image.buffer[2 * 7..3 * 7], &[b, c, a, b, c, a, b]);
+    }
+
+    #[test]
+    fn strip_checks_gutters_against_the_mode()
+    {
+        let input = sheet(4, 4);
+        let extrude_with = |mode| extrude(&input, 4, 4, &options(2, 2, 2, mode)).unwrap().image;
+        let mismatched = |image: &Image, mode| strip(&image.buffer, image.width, image.height, &options(2, 2, 2, mode)).unwrap().mismatched_tiles;
+
+        let color = ExtrudeMode::Color(RGBA { r: 1, g: 2, b: 3, a: 4 });
+        assert_eq!(mismatched(&extrude_with(color), color), vec![]);
+        assert_eq!(mismatched(&extrude_with(color), ExtrudeMode::Transparent), vec![0, 1, 2, 3]);
+        assert_eq!(mismatched(&extrude_with(ExtrudeMode::Wrap), ExtrudeMode::Clamp), vec![0, 1, 2, 3]);
+        assert_eq!(mismatched(&extrude_with(ExtrudeMode::Clamp), ExtrudeMode::Mirror), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn strip_undoes_extrude_of_padded_tiles()
+    {
+        // 7x5 pixels hold 2x2 whole 3x2 tiles, and a partial column and row
+        let input = sheet(7, 5);
+        let transparent = RGBA { r: 0, g: 0, b: 0, a: 0 };
+
+        for &mode in MODES
+        {
+            for &gutter in &[0, 1, 3]
+            {
+                let mut options = options(3, 2, gutter, mode);
+                options.partial_tiles = PartialTiles::Pad;
+
+                let extruded = extrude(&input, 7, 5, &options).unwrap();
+                assert_eq!((extruded.columns, extruded.rows, extruded.had_partial_tiles), (3, 3, true));
+
+                let stripped = strip(&extruded.image.buffer, extruded.image.width, extruded.image.height, &options).unwrap();
+                assert_eq!((stripped.image.width, stripped.image.height), (9, 6));
+                assert!(stripped.mismatched_tiles.is_empty());
+
+                for y in 0..6
+                {
+                    for x in 0..9
+                    {
+                        let expected = if x < 7 && y < 5 { input[y * 7 + x] } else { transparent };
+                        assert_eq!(stripped.image.buffer[y * 9 + x], expected, "{:?}, gutter {}, ({}, {})", mode, gutter, x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn partial_tiles()
+    {
+        let input = sheet(7, 5);
+
+        let options = options(3, 2, 1, ExtrudeMode::Clamp);
+        assert!(extrude(&input, 7, 5, &options).is_err());
+
+        let mut crop = options.clone();
+        crop.partial_tiles = PartialTiles::Crop;
+        let extruded = extrude(&input, 7, 5, &crop).unwrap();
+        assert_eq!((extruded.columns, extruded.rows, extruded.had_partial_tiles), (2, 2, true));
+
+        // No whole tiles at all, even when the margin is what's in the way
+        crop.tile_width = 8;
+        assert!(extrude(&input, 7, 5, &crop).is_err());
+
+        let mut margin = Options::new(4, 4);
+        margin.input_margin = 3;
+        assert!(extrude(&sheet(4, 4), 4, 4, &margin).is_err());
+
+        // Padding a lone partial tile, past a margin that fills the image
+        margin.partial_tiles = PartialTiles::Pad;
+        let extruded = extrude(&sheet(4, 4), 4, 4, &margin).unwrap();
+        assert_eq!((extruded.columns, extruded.rows, extruded.image.width), (1, 1, 6));
+
+        // Huge margins and spacing count no tiles instead of overflowing
+        margin.input_margin = usize::MAX;
+        assert!(matches!(extrude(&sheet(4, 4), 4, 4, &margin), Ok(Extruded { columns: 1, rows: 1, .. })));
+
+        margin.input_margin = 0;
+        margin.input_spacing = usize::MAX;
+        margin.partial_tiles = PartialTiles::Crop;
+        assert!(matches!(extrude(&sheet(8, 4), 8, 4, &margin), Ok(Extruded { columns: 1, rows: 1, .. })));
+    }
+
+    #[test]
+    fn bad_buffers_and_options_fail_without_panicking()
+    {
+        let input = sheet(4, 4);
+        let options = Options::new(2, 2);
+
+        assert_eq!((options.gutter, options.extrude_mode, options.partial_tiles), (1, ExtrudeMode::Clamp, PartialTiles::Error));
+
+        // Buffer sizes that don't match the dimensions
+        assert!(matches!(extrude(&input, 4, 3, &options), Err(Error::BadDimensions(_))));
+        assert!(matches!(strip(&input, 5, 4, &options), Err(Error::BadDimensions(_))));
+        assert!(matches!(extrude(&input, usize::MAX, 2, &options), Err(Error::BadDimensions(_))));
+        assert!(matches!(extrude_pixels(&[0u8; 3], 2, 2, &options, &Fill { transparent: 0, color: 0 }), Err(Error::BadDimensions(_))));
+
+        // Empty images hold no tiles
+        assert!(matches!(extrude(&[], 0, 0, &options), Err(Error::BadDimensions(_))));
+        assert!(matches!(strip(&[], 0, 0, &options), Err(Error::BadDimensions(_))));
+
+        for &(tile_width, tile_height) in &[(0, 2), (2, 0)]
+        {
+            let options = Options::new(tile_width, tile_height);
+            assert!(matches!(extrude(&input, 4, 4, &options), Err(Error::BadArguments(_))));
+            assert!(matches!(strip(&input, 4, 4, &options), Err(Error::BadArguments(_))));
+        }
+
+        // Tiles far larger than the image
+        let mut huge = Options::new(usize::MAX, usize::MAX);
+        huge.gutter = 0;
+        huge.partial_tiles = PartialTiles::Pad;
+        assert!(matches!(extrude(&input, 4, 4, &huge), Err(Error::BadDimensions(_))));
+    }
+}
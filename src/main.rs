@@ -1,294 +1,948 @@
+#![allow(clippy::needless_return)]
+
+extern crate glob;
 extern crate lodepng;
+extern crate notify;
+extern crate tilext;
+extern crate toml;
+
+mod args;
+mod cache;
+mod config_file;
+mod watch;
 
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::error::Error;
-use std::path::PathBuf;
-use std::ffi::OsString;
-use std::default::Default;
+use std::fs;
+use std::io;
+use std::process;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use lodepng::{ColorMode, ColorType};
+
+use tilext::{Error, Options, PartialTiles};
+use tilext::atlas::Atlas;
+use tilext::godot::AtlasSource;
+use tilext::png::{MARKER_KEYWORD, PngImage};
+use tilext::tiled::Tileset;
 
-use lodepng::RGBA;
+use args::{Backup, Command, Config, Manifest};
+use cache::Cache;
+use watch::Watch;
 
-struct Config<'a>
+/// Output of one file, printed in one piece so parallel jobs don't interleave
+type Log = Vec<String>;
+
+macro_rules! logln
 {
-    tile_size: usize,
-    output_suffix: &'a str,
-    make_backup: bool,
-    input_paths: Vec<PathBuf>
+    ($log:expr, $($arg:tt)*) => { $log.push(format!($($arg)*)) }
 }
 
-#[derive(Debug, PartialEq)]
-enum ArgsKey
+fn read_image(bytes: &[u8], log: &mut Log) -> Result<PngImage, Error>
 {
-    Default,
-    TileSize,
-    OutputSuffix
+    let image = PngImage::decode(bytes)?;
+
+    logln!(log, "  Read {}x{} pixels ({:?}, {}-bit) from file", image.width, image.height, image.color.colortype, image.color.bitdepth());
+
+    return Ok(image);
+}
+
+/// Strips or overrides the ancillary chunks carried over from the input
+fn apply_metadata(config: &Config, image: &mut PngImage, log: &mut Log)
+{
+    let metadata = &config.metadata;
+
+    if metadata.strip
+    {
+        image.chunks.clear();
+    }
+
+    if let Some(intent) = metadata.srgb
+    {
+        // sRGB and an ICC profile shouldn't both be present
+        image.remove_chunks(b"iCCP");
+        image.set_chunk(b"sRGB", vec![intent]);
+    }
+
+    if let Some(gamma) = metadata.gamma
+    {
+        image.set_chunk(b"gAMA", gamma.to_be_bytes().to_vec());
+    }
+
+    for (keyword, text) in &metadata.text
+    {
+        image.set_text(keyword, text);
+    }
+
+    if !image.chunks.is_empty()
+    {
+        let names: Vec<String> = image.chunks.iter().map(|c| String::from_utf8_lossy(&c.name).into_owned()).collect();
+        logln!(log, "  Writing chunks {}", names.join(", "));
+    }
 }
 
-fn parse_args<'a>(args: &'a Vec<String>) -> Result<Config<'a>, Box<Error>>
+/// Saves the input aside, as the backup policy says, if the output is going
+/// to replace it. Returns whether it does.
+fn write_backup(config: &Config, input_path: &Path, input: &[u8], output_path: &Path, log: &mut Log) -> Result<bool, Error>
 {
-    let mut tile_size: Option<usize> = None;
-    let mut output_suffix = "";
-    let mut input_paths = Vec::<PathBuf>::new();
-    let mut make_backup = false;
+    let overwrites_input = output_path.exists()
+        && fs::canonicalize(output_path).ok() == fs::canonicalize(input_path).ok();
 
-    use ArgsKey::*;
-    let mut current_key = Default;
-    for arg in args.as_slice()
+    if !overwrites_input
     {
-        match current_key
+        return Ok(false);
+    }
+
+    let backup_path = match config.backup
+    {
+        Backup::Never => return Ok(true),
+
+        Backup::Once =>
         {
-            TileSize =>
+            let path = args::backup_path(input_path, None)?;
+            if path.exists()
             {
-                tile_size = Some(arg.parse().map_err(
-                    |e| format!("{} (after --tile--size)", e)
-                )?);
-                current_key = Default;
-            },
+                logln!(log, "  Keeping existing backup {:?}", path.as_os_str());
+                return Ok(true);
+            }
+            path
+        },
 
-            OutputSuffix =>
+        Backup::Always =>
+        {
+            let path = args::backup_path(input_path, None)?;
+            if path.exists() && !config.force
             {
-                output_suffix = &arg;
-                current_key = Default;
-            },
+                return Err(Error::Write(format!(
+                    "Backup {:?} already exists and may hold the original image (use --force to replace it, or --backup numbered)", path)));
+            }
+            path
+        },
 
-            Default =>
+        Backup::Numbered =>
+        {
+            let mut number = 1;
+            while args::backup_path(input_path, Some(number))?.exists()
             {
-                if arg.starts_with("--")
-                {
-                    current_key = match &arg.chars().as_str()[2..]
-                    {
-                        "tile-size" => TileSize,
-                        "output-suffix" => OutputSuffix,
-                        s => {
-                            println!("Warning: Argument key {} is unknown (ignoring)", s);
-                            Default
-                        }
-                    };
-                }
-                else
-                {
-                    input_paths.push(PathBuf::from(&arg));
-                }
+                number += 1;
             }
+            args::backup_path(input_path, Some(number))?
         }
+    };
+
+    tilext::write_atomic(&backup_path, input)?;
+
+    logln!(log, "  Copied input to {:?}", backup_path.as_os_str());
+
+    return Ok(true);
+}
+
+/// Writes the output image, and returns the color mode it was written in
+fn write_output(config: &Config, output_path: &Path, image: &PngImage, log: &mut Log) -> Result<ColorMode, Error>
+{
+    if let Some(dir) = output_path.parent().filter(|d| !d.as_os_str().is_empty())
+    {
+        fs::create_dir_all(dir).map_err(|e| Error::Write(format!("{} ({:?})", e, dir)))?;
     }
 
-    if current_key != Default
+    let color = image.write(output_path, config.output_color)?;
+
+    logln!(log, "  Wrote {}x{} pixels to {:?}", image.width, image.height, output_path.as_os_str());
+
+    return Ok(color);
+}
+
+fn extrude_image(options: &Options, image: &PngImage, log: &mut Log) -> Result<PngImage, Error>
+{
+    logln!(log, "  Processing with tile size {}x{}", options.tile_width, options.tile_height);
+
+    let extruded = image.extrude(options)?;
+
+    if extruded.image.color.colortype != image.color.colortype
+    {
+        logln!(log, "  Converted to {:?}, as the gutter or padding color can't be represented in {:?}",
+            extruded.image.color.colortype, image.color.colortype);
+    }
+
+    if extruded.had_partial_tiles
+    {
+        match options.partial_tiles
+        {
+            PartialTiles::Pad => logln!(log, "  Padded partial tiles with transparent pixels"),
+            _ => logln!(log, "  Cropped partial tiles")
+        }
+    }
+
+    if options.input_margin > 0 || options.input_spacing > 0
+    {
+        logln!(log, "  Unpacked tiles from margin {} and spacing {}", options.input_margin, options.input_spacing);
+    }
+
+    logln!(log, "  Extruded {} ({}*{}) tiles into {}-pixel gutters ({:?})",
+        extruded.columns*extruded.rows, extruded.columns, extruded.rows, options.gutter, options.extrude_mode);
+
+    return Ok(extruded.image);
+}
+
+fn strip_image(options: &Options, image: &PngImage, log: &mut Log) -> Result<PngImage, Error>
+{
+    logln!(log, "  Stripping {}-pixel gutters with tile size {}x{}", options.gutter, options.tile_width, options.tile_height);
+
+    let stripped = image.strip(options)?;
+
+    logln!(log, "  Removed gutters from {} ({}*{}) tiles", stripped.columns*stripped.rows, stripped.columns, stripped.rows);
+
+    if !stripped.mismatched_tiles.is_empty()
+    {
+        logln!(log, "  Warning: Gutters don't match tile edges in {} tile(s): {:?}", stripped.mismatched_tiles.len(), stripped.mismatched_tiles);
+    }
+
+    return Ok(stripped.image);
+}
+
+/// Turns errors about a Tiled map or tileset, or a Godot TileSet resource,
+/// into ones that name it
+fn in_tileset(path: &Path) -> impl Fn(Error) -> Error + '_
+{
+    move |e| match e
     {
-        return Err(format!("Expected another argument (type {:?})", current_key).into());
+        Error::Tileset(message) => Error::Tileset(format!("{} ({:?})", message, path)),
+        // Already names the file
+        Error::Write(message) => Error::Tileset(message),
+        e => e
     }
+}
+
+/// Path of the Tiled tileset written next to an output with --tsx
+fn tsx_path(output_path: &Path) -> PathBuf
+{
+    output_path.with_extension("tsx")
+}
+
+/// Writes a Tiled tileset for the output, or updates the one that's there
+fn write_tsx(options: &Options, gutter: usize, output_path: &Path, image: &PngImage, log: &mut Log) -> Result<(), Error>
+{
+    let tsx_path = tsx_path(output_path);
+    let name = output_path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let image_source = output_path.file_name().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let tileset = Tileset::new(&name, &image_source, image.width, image.height, options, gutter);
+
+    let existing = match fs::read_to_string(&tsx_path)
+    {
+        Ok(tsx) => Some(tsx),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(Error::Tileset(format!("{} ({:?})", e, tsx_path)))
+    };
+
+    match existing
+    {
+        Some(tsx) =>
+        {
+            let tsx = tileset.update_tsx(&tsx).map_err(in_tileset(&tsx_path))?;
+            tilext::write_atomic(&tsx_path, tsx.as_bytes()).map_err(in_tileset(&tsx_path))?;
+            logln!(log, "  Updated tileset {:?}", tsx_path.as_os_str());
+        },
+
+        None =>
+        {
+            tilext::write_atomic(&tsx_path, tileset.to_tsx().as_bytes()).map_err(in_tileset(&tsx_path))?;
+            logln!(log, "  Wrote tileset {:?}", tsx_path.as_os_str());
+        }
+    }
+
+    return Ok(());
+}
 
-    if input_paths.len() == 0
+/// Path from a directory to a file, both canonical, with / separators as
+/// Tiled writes them
+fn relative_path(from_dir: &Path, to: &Path) -> String
+{
+    let from: Vec<Component> = from_dir.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|&(a, b)| a == b).count();
+
+    let mut parts: Vec<String> = from[common..].iter().map(|_| "..".to_string()).collect();
+    parts.extend(to[common..].iter().map(|c| c.as_os_str().to_string_lossy().into_owned()));
+    return parts.join("/");
+}
+
+/// Layout of an output for --update-tiled, with the canonical paths of its
+/// input and output
+struct TiledImage
+{
+    tileset: Tileset,
+    input_path: PathBuf,
+    output_path: PathBuf
+}
+
+fn tiled_image(config: &Config, input_path: &Path) -> Result<TiledImage, Error>
+{
+    let options = &config.settings_for(input_path).options;
+    let output_path = config.output_path(input_path)?;
+    let image = PngImage::read(&output_path)?;
+    let gutter = if config.strip { 0 } else { options.gutter };
+
+    return Ok(TiledImage
+    {
+        tileset: Tileset::new("", "", image.width, image.height, options, gutter),
+        input_path: watch::canonical(input_path),
+        output_path: watch::canonical(&output_path)
+    });
+}
+
+/// Updates the tilesets of a Tiled map or tileset that use one of the images.
+/// Tilesets using an input are pointed at its output. Returns how many
+/// tilesets changed.
+fn update_tiled_file(path: &Path, images: &[TiledImage]) -> Result<usize, Error>
+{
+    let mut xml = fs::read_to_string(path).map_err(|e| Error::Tileset(format!("{} ({:?})", e, path)))?;
+    let dir = watch::canonical(path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or_else(|| Path::new(".")));
+    let mut count = 0;
+
+    for image in images
     {
-        return Err("No file paths specified".into());
+        let (updated, n) = image.tileset.update_references(&xml, |source|
+        {
+            let source_path = watch::canonical(&dir.join(source));
+            if source_path == image.output_path
+            {
+                Some(source.to_string())
+            }
+            else if source_path == image.input_path
+            {
+                Some(relative_path(&dir, &image.output_path))
+            }
+            else
+            {
+                None
+            }
+        });
+
+        xml = updated;
+        count += n;
     }
 
-    if output_suffix == ""
+    if count > 0
     {
-        make_backup = true;
-        println!("Warning: No output suffix specified. Input file will be overwritten, and a backup (with suffix _backup) will be made. (use --output-suffix if you meant to specify a suffix)");
+        tilext::write_atomic(path, xml.as_bytes()).map_err(in_tileset(path))?;
     }
 
-    let c = Config
+    return Ok(count);
+}
+
+/// Points the tilesets in the --update-tiled directories that use the inputs
+/// or outputs of files at the outputs, with their new size and gutter layout.
+/// Returns the files that failed.
+fn update_tiled(config: &Config, inputs: &[&PathBuf]) -> Vec<(PathBuf, Error)>
+{
+    let mut failed = Vec::new();
+
+    let files = match args::find_tiled_files(&config.update_tiled)
     {
-        tile_size: tile_size.ok_or("No tile size specified (use --tile-size)")?,
-        output_suffix,
-        make_backup,
-        input_paths
+        Ok(files) => files,
+        Err(e) => return vec![(config.update_tiled[0].clone(), e)]
     };
-    return Ok(c);
+
+    let mut images = Vec::new();
+    for &path in inputs
+    {
+        match tiled_image(config, path)
+        {
+            Ok(image) => images.push(image),
+            Err(e) => failed.push((path.clone(), e))
+        }
+    }
+
+    for path in files
+    {
+        match update_tiled_file(&path, &images)
+        {
+            Ok(0) => {},
+            Ok(count) => println!("Updated {} Tiled tileset(s) in {:?}", count, path),
+
+            Err(e) =>
+            {
+                eprintln!("Error: {}", e);
+                failed.push((path, e));
+            }
+        }
+    }
+
+    return failed;
+}
+
+/// Tiles of an output image
+fn output_atlas(config: &Config, options: &Options, image: &PngImage) -> Atlas
+{
+    if config.strip
+    {
+        Atlas::stripped(image.width, image.height, options)
+    }
+    else
+    {
+        Atlas::extruded(image.width, image.height, options)
+    }
 }
 
-fn get_pixel_index(pixel_x: usize, pixel_y: usize, image_width: usize) -> usize
+/// Path of the Godot TileSet written next to an output with --godot
+fn tres_path(output_path: &Path) -> PathBuf
 {
-    pixel_y * image_width + pixel_x
+    output_path.with_extension("tres")
 }
 
-fn insert_pixels(buf: &mut Vec<RGBA>, pos: usize, mut pixels: Vec<RGBA>) -> usize
+/// Path Godot knows the output by: a res:// path if it's inside a Godot
+/// project, or else its path relative to the TileSet
+fn godot_texture_path(output_path: &Path) -> String
 {
-    let count = pixels.len();
+    let output_path = watch::canonical(output_path);
 
-    if pos >= buf.len()
+    for dir in output_path.ancestors().skip(1)
     {
-        buf.append(&mut pixels);
+        if dir.join("project.godot").is_file()
+        {
+            return format!("res://{}", relative_path(dir, &output_path));
+        }
+    }
+
+    return output_path.file_name().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+}
+
+/// Writes a Godot TileSet for the output, or with --godot-merge updates the
+/// one that's there
+fn write_tres(config: &Config, atlas: &Atlas, output_path: &Path, log: &mut Log) -> Result<(), Error>
+{
+    let tres_path = tres_path(output_path);
+    let source = AtlasSource::new(&godot_texture_path(output_path), atlas);
+
+    if config.godot_merge && tres_path.exists()
+    {
+        let tres = fs::read_to_string(&tres_path).map_err(|e| Error::Tileset(format!("{} ({:?})", e, tres_path)))?;
+        let tres = source.merge_tres(&tres).map_err(in_tileset(&tres_path))?;
+        tilext::write_atomic(&tres_path, tres.as_bytes()).map_err(in_tileset(&tres_path))?;
+        logln!(log, "  Updated Godot TileSet {:?}", tres_path.as_os_str());
     }
     else
     {
-        let removed = buf.splice(pos..pos, pixels);
-        assert!(removed.count() == 0);
+        tilext::write_atomic(&tres_path, source.to_tres().as_bytes()).map_err(in_tileset(&tres_path))?;
+        logln!(log, "  Wrote Godot TileSet {:?}", tres_path.as_os_str());
+    }
+
+    return Ok(());
+}
+
+fn manifest_path(manifest: Manifest, output_path: &Path) -> PathBuf
+{
+    output_path.with_extension(manifest.extension())
+}
+
+/// Files written next to an output: its tilesets and manifests
+fn side_paths(config: &Config, output_path: &Path) -> Vec<PathBuf>
+{
+    let mut paths: Vec<PathBuf> = config.manifests.iter().map(|&m| manifest_path(m, output_path)).collect();
+    if config.tsx
+    {
+        paths.push(tsx_path(output_path));
+    }
+    if config.godot
+    {
+        paths.push(tres_path(output_path));
+    }
+
+    return paths;
+}
+
+/// Names of the tiles in TexturePacker manifests, from --frame-name
+fn frame_names(config: &Config, input_path: &Path, atlas: &Atlas) -> Result<Vec<String>, Error>
+{
+    let stem = input_path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let mut names = Vec::with_capacity(atlas.tiles.len());
+    let mut seen = HashSet::with_capacity(atlas.tiles.len());
+
+    for tile in &atlas.tiles
+    {
+        let name = args::expand_frame_name(&config.frame_name, &stem, tile.index, tile.column, tile.row)?;
+        if !seen.insert(name.clone())
+        {
+            return Err(Error::BadArguments(format!("Several tiles are named {} (use {{index}} in --frame-name)", name)));
+        }
+        names.push(name);
     }
 
-    return count;
+    return Ok(names);
+}
+
+/// TexturePacker's name for the pixel format of an output, if it has one
+fn texture_packer_format(color: &ColorMode) -> Option<&'static str>
+{
+    match (color.colortype, color.bitdepth())
+    {
+        (ColorType::RGBA, 8) => Some("RGBA8888"),
+        (ColorType::RGB, 8) => Some("RGB888"),
+        _ => None
+    }
 }
 
-fn process_image(config: &Config, path_i: usize) -> Result<(), Box<Error>>
+/// Writes a manifest of an output, written in the color mode `color`
+fn write_manifest(config: &Config, manifest: Manifest, input_path: &Path, output_path: &Path, image: &PngImage, color: &ColorMode, log: &mut Log) -> Result<(), Error>
 {
-    let input_path = &config.input_paths[path_i];
+    let options = &config.settings_for(input_path).options;
+    let manifest_path = manifest_path(manifest, output_path);
+    let image_name = output_path.file_name().map(|s| s.to_string_lossy()).unwrap_or_default();
+
+    let atlas = output_atlas(config, options, image);
+
+    let text = match manifest
+    {
+        Manifest::Json => atlas.to_json(&image_name, config.half_texel_inset),
+        Manifest::TexturePackerHash =>
+            atlas.to_texture_packer(&image_name, &frame_names(config, input_path, &atlas)?, texture_packer_format(color), false),
+        Manifest::TexturePackerArray =>
+            atlas.to_texture_packer(&image_name, &frame_names(config, input_path, &atlas)?, texture_packer_format(color), true),
+        Manifest::Rust => atlas.to_rust(&image_name, &config.tile_names, config.half_texel_inset)?
+    };
+
+    tilext::write_atomic(&manifest_path, text.as_bytes())?;
+
+    logln!(log, "  Wrote manifest of {} tiles to {:?}", atlas.tiles.len(), manifest_path.as_os_str());
+
+    return Ok(());
+}
+
+/// Hash of every setting that changes the output of a file
+fn config_hash(config: &Config, input_path: &Path, output_path: &Path) -> u64
+{
+    let key = format!("{} {:?} {:?} {:?} {} {} {} {} {} {:?} {} {} {:?} {:?}", env!("CARGO_PKG_VERSION"), config.settings_for(input_path).options,
+        config.output_color, config.metadata, config.strip, config.marker, config.tsx, config.godot, config.godot_merge,
+        config.manifests, config.half_texel_inset, config.frame_name, config.tile_names, output_path);
+    return cache::hash(key.as_bytes());
+}
+
+/// What happened to a file that didn't fail
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome
+{
+    Written,
+    UpToDate,
+    /// Already extruded
+    Skipped
+}
+
+fn process_file(config: &Config, cache: Option<&Mutex<&mut Cache>>, input_path: &Path, log: &mut Log) -> Result<Outcome, Error>
+{
+    let options = &config.settings_for(input_path).options;
+    let output_path = config.output_path(input_path)?;
+
+    logln!(log, "File: {:?}:", input_path);
 
-    println!("File: {:?}:", input_path);
-    println!("  Processing with tile size {}", config.tile_size);
+    //
+    // Read file, unless the output is already up to date
+    //
+
+    let bytes = fs::read(input_path).map_err(|e| Error::Decode(format!("{} ({:?})", e, input_path)))?;
+    let input_hash = cache::hash(&bytes);
+    let config_hash = config_hash(config, input_path, &output_path);
+
+    let side_files_missing = side_paths(config, &output_path).iter().any(|path| !path.exists());
+    if !side_files_missing && cache.is_some_and(|c| c.lock().unwrap().is_up_to_date(input_path, input_hash, config_hash, &output_path))
+    {
+        logln!(log, "  Up to date with {:?}, skipping", output_path.as_os_str());
+        return Ok(Outcome::UpToDate);
+    }
+
+    let mut image = read_image(&bytes, log)?;
 
     //
-    // Read file
+    // Skip images that were already extruded, so in-place runs don't
+    // extrude them twice
     //
 
-    let mut image = lodepng::decode32_file(input_path)?;
+    if !config.strip && !config.allow_reextrude
+    {
+        if let Some(marker) = image.text(MARKER_KEYWORD)
+        {
+            logln!(log, "  Warning: Already extruded by tilext ({}), skipping (use --allow-reextrude to extrude again)", marker);
+            return Ok(Outcome::Skipped);
+        }
+
+        if image.looks_extruded(options)
+        {
+            logln!(log, "  Warning: Gutters already match the tile edges, so the image looks extruded, skipping (use --allow-reextrude to extrude anyway)");
+            return Ok(Outcome::Skipped);
+        }
+    }
 
-    println!("  Read {} pixels from file", image.buffer.len());
+    apply_metadata(config, &mut image, log);
 
     //
-    // Make backup if necessary
+    // Extrude tiles or remove gutters
     //
 
-    if config.make_backup
+    let mut output = if config.strip
+    {
+        strip_image(options, &image, log)?
+    }
+    else
     {
-        let mut backup_path = input_path.clone();
-        let mut backup_name = backup_path.file_stem().ok_or("Invalid path")?.to_os_string();
-        backup_name.push("_backup");
-        backup_name.push(".png");
-        backup_path.set_file_name(backup_name);
+        extrude_image(options, &image, log)?
+    };
 
-        lodepng::encode32_file(&backup_path, &image.buffer, image.width, image.height)?;
+    // A marker from an earlier extrusion no longer applies
+    output.remove_text(MARKER_KEYWORD);
 
-        println!("  Wrote {} pixels to {:?}", image.buffer.len(), OsString::from(backup_path));
+    if config.marker && !config.strip
+    {
+        let marker = format!("tile={}x{} gutter={}", options.tile_width, options.tile_height, options.gutter);
+        logln!(log, "  Marked as extruded ({})", marker);
+        output.set_text(MARKER_KEYWORD, &marker);
+    }
+
+    // A TileSet edited in Godot would lose its physics, terrains and other
+    // data, so check before anything is written
+    let tres_path = tres_path(&output_path);
+    if config.godot && !config.godot_merge && !config.force && tres_path.exists()
+    {
+        return Err(Error::Tileset(format!(
+            "Godot TileSet {:?} already exists (use --godot-merge to update it, or --force to replace it)", tres_path)));
     }
 
     //
-    // Resize image
+    // Make backup if necessary, now that there's an output to replace the
+    // input with
     //
 
-    let columns = image.width / config.tile_size;
-    let rows = image.height / config.tile_size;
+    let in_place = write_backup(config, input_path, &bytes, &output_path, log)?;
 
-    let new_width = image.width + columns * 2;
-    let new_height = image.height + rows * 2;
+    //
+    // Write to file
+    //
 
-    println!("new_width: {:?}, new_height: {:?}", new_width, new_height);
+    let color = write_output(config, &output_path, &output, log)?;
 
-    let to_insert = new_width * rows * 2
-                    + rows * config.tile_size * columns * 2;
-    image.buffer.reserve_exact(to_insert);
+    if config.tsx
+    {
+        let gutter = if config.strip { 0 } else { options.gutter };
+        write_tsx(options, gutter, &output_path, &output, log)?;
+    }
 
-    let mut inserted = 0;
+    if config.godot
+    {
+        write_tres(config, &output_atlas(config, options, &output), &output_path, log)?;
+    }
 
-    for i in 0..image.buffer.len()
+    for &manifest in &config.manifests
     {
-        let pixel_x = i % image.width;
+        write_manifest(config, manifest, input_path, &output_path, &output, &color, log)?;
+    }
+
+    if let Some(cache) = cache
+    {
+        cache.lock().unwrap().record(input_path, input_hash, config_hash, &output_path, in_place);
+    }
 
-        if pixel_x == 0 // at start of pixel row
+    return Ok(Outcome::Written);
+}
+
+/// Process exit code for an error; the first failing file decides the code
+fn exit_code(error: &Error) -> i32
+{
+    match *error
+    {
+        Error::BadArguments(_) => 2,
+        Error::Decode(_) => 3,
+        Error::BadDimensions(_) => 4,
+        Error::Write(_) => 5,
+        Error::Tileset(_) => 6
+    }
+}
+
+/// Processes files on `config.jobs` threads and prints a summary. Each file's
+/// log is printed in one piece once it's done, and the summary follows the
+/// order of `paths`. Returns the first error, if any.
+fn run_batch(config: &Config, cache: Option<&mut Cache>, paths: &[PathBuf]) -> Option<Error>
+{
+    let jobs = config.jobs.clamp(1, paths.len().max(1));
+    let cache = cache.map(Mutex::new);
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<Result<Outcome, Error>>> = paths.iter().map(|_| None).collect();
+
+    thread::scope(|scope|
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        for _ in 0..jobs
         {
-            let row = i / image.width;
+            let sender = sender.clone();
+            let (cache, next) = (&cache, &next);
 
-            if row % config.tile_size == 0 // at start of tile row
+            scope.spawn(move ||
             {
-                if i == 0 // at first pixel row
-                {
-                    inserted += insert_pixels(&mut image.buffer, i+inserted, vec![Default::default(); new_width-1]);
-                }
-                else
+                loop
                 {
-                    inserted += insert_pixels(&mut image.buffer, i+inserted, vec![Default::default(); new_width * 2]);
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= paths.len()
+                    {
+                        break;
+                    }
+
+                    let mut log = Log::new();
+                    let result = process_file(config, cache.as_ref(), &paths[i], &mut log);
+                    if sender.send((i, log, result)).is_err()
+                    {
+                        break;
+                    }
                 }
+            });
+        }
+        drop(sender);
+
+        for (i, log, result) in receiver
+        {
+            for line in log
+            {
+                println!("{}", line);
+            }
+
+            if let Err(ref e) = result
+            {
+                eprintln!("Error: {}", e);
             }
 
-            inserted += insert_pixels(&mut image.buffer, i+inserted, vec![Default::default(); 2]);
+            results[i] = Some(result);
         }
-        else if pixel_x % config.tile_size == 0 // at start of tile column
+    });
+
+    let mut succeeded = Vec::new();
+    let mut outputs = Vec::new();
+    let mut up_to_date = 0;
+    let mut skipped = 0;
+    let mut failed = Vec::new();
+
+    for (path, result) in paths.iter().zip(results)
+    {
+        match result.expect("every file has a result")
         {
-            inserted += insert_pixels(&mut image.buffer, i+inserted, vec![Default::default(); 2]);
+            Ok(outcome) =>
+            {
+                match outcome
+                {
+                    Outcome::UpToDate => up_to_date += 1,
+                    Outcome::Skipped => skipped += 1,
+                    Outcome::Written => {}
+                }
+
+                // Outputs left alone still count, so a re-run with
+                // --update-tiled catches up on maps an earlier run missed
+                if outcome == Outcome::Written || config.output_path(path).is_ok_and(|output| output.is_file())
+                {
+                    outputs.push(path);
+                }
+                succeeded.push(path);
+            },
+
+            Err(e) => failed.push((path.clone(), e))
         }
     }
 
-    let pos = image.buffer.len();
-    inserted += insert_pixels(&mut image.buffer, pos, vec![Default::default(); new_width+1]);
+    if !config.update_tiled.is_empty() && !outputs.is_empty()
+    {
+        failed.extend(update_tiled(config, &outputs));
+    }
+
+    //
+    // Summary
+    //
+
+    if let Some(cache) = cache
+    {
+        if let Err(e) = cache.into_inner().unwrap().save()
+        {
+            eprintln!("Error: {}", e);
+            failed.push((config.cache_path.clone().unwrap(), e));
+        }
+    }
 
-    println!("  Resized image, inserting {} pixels", inserted);
+    println!();
+    let mut notes = Vec::new();
+    if up_to_date > 0
+    {
+        notes.push(format!("{} up to date", up_to_date));
+    }
+    if skipped > 0
+    {
+        notes.push(format!("{} skipped as already extruded", skipped));
+    }
 
-    assert!(inserted == to_insert);
-    assert!(image.buffer.len() % new_width == 0);
-    assert!(new_height == image.buffer.len() / new_width);
+    if notes.is_empty()
+    {
+        println!("Summary: {} succeeded, {} failed", succeeded.len(), failed.len());
+    }
+    else
+    {
+        println!("Summary: {} succeeded ({}), {} failed", succeeded.len(), notes.join(", "), failed.len());
+    }
 
-    image.height = new_height;
-    image.width = new_width;
+    for path in &succeeded
+    {
+        println!("  ok:     {:?}", path);
+    }
 
-    //
-    // Extrude tiles
-    //
+    for (path, e) in &failed
+    {
+        println!("  failed: {:?} ({})", path, e);
+    }
 
-    let tile_size_with_gutters = config.tile_size + 2;
-    let tile_pixel_max = tile_size_with_gutters - 1;
+    return failed.into_iter().next().map(|(_, e)| e);
+}
 
-    println!("  Extruding {} ({}*{}) tiles into 1-pixel gutters", columns*rows, columns, rows);
+/// Directories to watch for the inputs, and whether to watch them recursively
+fn watch_dirs(config: &Config) -> Vec<(PathBuf, bool)>
+{
+    let mut dirs = Vec::new();
 
-    for tile_row_i in 0..rows
+    for input in &config.inputs
     {
-        for tile_column_i in 0..columns
+        let path = Path::new(input);
+
+        let dir = if path.is_dir()
+        {
+            (path.to_path_buf(), config.recursive)
+        }
+        else if input.contains(['*', '?', '['])
         {
-            for tile_pixel_y in 0..tile_size_with_gutters
+            // Everything up to the first component with a wildcard
+            let base: PathBuf = path.components()
+                .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+                .collect();
+            (if base.as_os_str().is_empty() { PathBuf::from(".") } else { base }, true)
+        }
+        else
+        {
+            match path.parent().filter(|p| !p.as_os_str().is_empty())
             {
-                let pixel_y = tile_pixel_y + tile_row_i * tile_size_with_gutters;
+                Some(parent) => (parent.to_path_buf(), false),
+                None => (PathBuf::from("."), false)
+            }
+        };
 
-                for tile_pixel_x in 0..tile_size_with_gutters
-                {
-                    let pixel_x = tile_pixel_x + tile_column_i * tile_size_with_gutters;
+        if !dirs.contains(&dir)
+        {
+            dirs.push(dir);
+        }
+    }
 
-                    let from_i = match tile_pixel_y
-                    {
-                        0 => match tile_pixel_x
-                        {
-                            0 =>
-                                Some(get_pixel_index(pixel_x+1, pixel_y+1, image.width)),
-                            v if (v == tile_pixel_max) =>
-                                Some(get_pixel_index(pixel_x-1, pixel_y+1, image.width)),
-                            _ =>
-                                Some(get_pixel_index(pixel_x, pixel_y+1, image.width))
-                        },
-                        v if (v == tile_pixel_max) => match tile_pixel_x
-                        {
-                            0 =>
-                                Some(get_pixel_index(pixel_x+1, pixel_y-1, image.width)),
-                            v if (v == tile_pixel_max) =>
-                                Some(get_pixel_index(pixel_x-1, pixel_y-1, image.width)),
-                            _ =>
-                                Some(get_pixel_index(pixel_x, pixel_y-1, image.width))
-                        },
-                        _ => match tile_pixel_x
-                        {
-                            0 =>
-                                Some(get_pixel_index(pixel_x+1, pixel_y, image.width)),
-                            v if (v == tile_pixel_max) =>
-                                Some(get_pixel_index(pixel_x-1, pixel_y, image.width)),
-                            _ =>
-                                None
-                        }
-                    };
-
-                    if let Some(from_i) = from_i
-                    {
-                        let to_i = get_pixel_index(pixel_x, pixel_y, image.width);
-                        let from = image.buffer[from_i].clone();
-                        image.buffer[to_i] = from;
-                    }
-                }
+    return dirs;
+}
+
+/// Remembers the contents of processed inputs, their outputs and backups, so
+/// that --watch can ignore events for files it wrote itself or that didn't
+/// change
+fn remember_contents(config: &Config, paths: &[PathBuf], contents: &mut HashMap<PathBuf, u64>)
+{
+    let mut remember = |path: &Path|
+    {
+        if let Ok(bytes) = fs::read(path)
+        {
+            contents.insert(watch::canonical(path), cache::hash(&bytes));
+        }
+    };
+
+    for path in paths
+    {
+        remember(path);
+        if let Ok(output_path) = config.output_path(path)
+        {
+            remember(&output_path);
+        }
+
+        if let Ok(backup_path) = args::backup_path(path, None)
+        {
+            remember(&backup_path);
+        }
+
+        let mut number = 1;
+        while let Ok(backup_path) = args::backup_path(path, Some(number))
+        {
+            if !backup_path.exists()
+            {
+                break;
             }
+
+            remember(&backup_path);
+            number += 1;
         }
     }
+}
 
-    //
-    // Write to file
-    //
+/// Processes inputs again whenever they change. Only returns if watching fails.
+fn watch_inputs(config: &Config, mut cache: Option<Cache>) -> Error
+{
+    let watch = match Watch::new(&watch_dirs(config))
+    {
+        Ok(watch) => watch,
+        Err(e) => return e
+    };
 
-    let mut output_path = input_path.clone();
-    let mut output_name = output_path.file_stem().ok_or("Invalid path")?.to_os_string();
-    output_name.push(config.output_suffix);
-    output_name.push(".png");
-    output_path.set_file_name(output_name);
+    let mut contents = HashMap::new();
+    remember_contents(config, &config.input_paths, &mut contents);
 
-    lodepng::encode32_file(&output_path, &image.buffer, image.width, image.height)?;
+    println!();
+    println!("Watching for changes (press Ctrl+C to stop)");
 
-    println!("  Wrote {} pixels to {:?}", image.buffer.len(), OsString::from(output_path));
+    loop
+    {
+        let changed = match watch.wait_for_changes()
+        {
+            Ok(changed) => changed,
+            Err(e) => return e
+        };
 
-    return Ok(());
+        // Find inputs again, as new files may match a directory or pattern
+        let inputs = match args::find_inputs(&config.inputs, config.recursive, &config.excludes)
+        {
+            Ok(inputs) => config.without_outputs(inputs),
+
+            Err(e) =>
+            {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+
+        if let Err(e) = config.check_outputs(&inputs)
+        {
+            eprintln!("Error: {}", e);
+            continue;
+        }
+
+        let paths: Vec<PathBuf> = inputs.into_iter().filter(|path|
+        {
+            let canonical = watch::canonical(path);
+            changed.contains(&canonical)
+                && fs::read(path).ok().map(|bytes| cache::hash(&bytes)) != contents.get(&canonical).cloned()
+        }).collect();
+
+        if paths.is_empty()
+        {
+            continue;
+        }
+
+        println!();
+        run_batch(config, cache.as_mut(), &paths);
+        remember_contents(config, &paths, &mut contents);
+    }
+}
+
+/// An empty directory for a test, removed first if an earlier run left it
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf
+{
+    let dir = env::temp_dir().join(format!("tilext-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    return dir;
 }
 
 fn main()
@@ -296,23 +950,110 @@ fn main()
     println!();
 
     let args: Vec<String> = env::args().skip(1).collect();
-    match parse_args(&args)
+    let config = match args::parse_args(&args)
     {
-        Ok(config) =>
+        Ok(Command::Run(config)) => *config,
+
+        Ok(Command::Help) =>
         {
-            for i in 0..config.input_paths.len()
-            {
-                if let Err(e) = process_image(&config, i)
-                {
-                    eprintln!("Error: {}", e);
-                }
-            }
+            print!("{}", args::help());
+            return;
+        },
+
+        Ok(Command::Version) =>
+        {
+            println!("tilext {}", env!("CARGO_PKG_VERSION"));
+            return;
         },
 
         Err(e) =>
         {
             eprintln!("Error: {}", e);
+            process::exit(exit_code(&e));
         }
     };
 
+    let mut cache = match config.cache_path
+    {
+        Some(ref path) => match Cache::load(path)
+        {
+            Ok(cache) => Some(cache),
+
+            Err(e) =>
+            {
+                eprintln!("Error: {}", e);
+                process::exit(exit_code(&e));
+            }
+        },
+        None => None
+    };
+
+    let error = run_batch(&config, cache.as_mut(), &config.input_paths);
+
+    if config.watch
+    {
+        let e = watch_inputs(&config, cache);
+        eprintln!("Error: {}", e);
+        process::exit(exit_code(&e));
+    }
+
+    if let Some(e) = error
+    {
+        process::exit(exit_code(&e));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn config(a: &[&str]) -> Config
+    {
+        let a: Vec<String> = a.iter().map(|&arg| arg.into()).collect();
+        match args::parse_args(&a).unwrap()
+        {
+            Command::Run(config) => *config,
+            _ => panic!("Expected a run of {:?}", a)
+        }
+    }
+
+    #[test]
+    fn frame_names_from_the_template()
+    {
+        let mut options = Options::new(4, 4);
+        options.gutter = 1;
+        let atlas = Atlas::extruded(12, 12, &options);
+        let input_path = Path::new("art/tiles.png");
+
+        let names = frame_names(&config(&["-t", "4", "a.png"]), input_path, &atlas).unwrap();
+        assert_eq!(names, ["tiles_0", "tiles_1", "tiles_2", "tiles_3"]);
+
+        let names = frame_names(&config(&["-t", "4", "--frame-name", "{row}/{column}.png", "a.png"]), input_path, &atlas).unwrap();
+        assert_eq!(names, ["0/0.png", "0/1.png", "1/0.png", "1/1.png"]);
+
+        match frame_names(&config(&["-t", "4", "--frame-name", "{stem}_{row}", "a.png"]), input_path, &atlas)
+        {
+            Err(Error::BadArguments(message)) => assert!(message.starts_with("Several tiles are named tiles_0"), "{}", message),
+            result => panic!("{:?}", result)
+        }
+    }
+
+    #[test]
+    fn texture_packer_formats()
+    {
+        let color = |colortype, bitdepth|
+        {
+            let mut color = ColorMode::new();
+            color.colortype = colortype;
+            color.set_bitdepth(bitdepth);
+            color
+        };
+
+        assert_eq!(texture_packer_format(&color(ColorType::RGBA, 8)), Some("RGBA8888"));
+        assert_eq!(texture_packer_format(&color(ColorType::RGB, 8)), Some("RGB888"));
+        assert_eq!(texture_packer_format(&color(ColorType::RGBA, 16)), None);
+        assert_eq!(texture_packer_format(&color(ColorType::PALETTE, 4)), None);
+        assert_eq!(texture_packer_format(&color(ColorType::GREY, 8)), None);
+    }
 }
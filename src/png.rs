@@ -0,0 +1,838 @@
+//! Reading and writing PNG files without converting them to 8-bit RGBA, so
+//! indexed, greyscale and 16-bit images are extruded in their own format
+
+use std::fs;
+use std::path::Path;
+
+use lodepng::{self, ColorMode, ColorType, Decoder, Encoder};
+use rgb::ComponentBytes;
+
+use {Error, Extruded, ExtrudeMode, Fill, Image, Options, PartialTiles, RGBA, Stripped};
+
+/// Color type and bit depth of the written file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputColor
+{
+    /// Same as the input
+    Keep,
+    /// Smallest type that holds all colors of the image
+    Auto,
+    /// A given color type and bit depth
+    Convert(ColorType, u32)
+}
+
+/// Where an ancillary chunk goes in the file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Placement
+{
+    /// Before PLTE (where sRGB, gAMA, iCCP and cHRM have to be)
+    AfterHeader,
+    /// Between PLTE and IDAT
+    AfterPalette,
+    /// After the image data
+    AfterData
+}
+
+/// An ancillary chunk (sRGB, gAMA, iCCP, pHYs, tEXt, ...) carried from the
+/// input file to the output
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk
+{
+    pub name: [u8; 4],
+    pub data: Vec<u8>,
+    pub placement: Placement
+}
+
+/// Keyword of the tEXt chunk recording the tile size and gutter an image was
+/// extruded with
+pub const MARKER_KEYWORD: &str = "tilext";
+
+/// Chunks whose contents depend on the color type, so they can't be kept
+/// once the image is converted
+const COLOR_CHUNKS: [&[u8; 4]; 3] = [b"bKGD", b"sBIT", b"hIST"];
+
+/// Chunks that aren't safe to copy into an image whose pixels changed, but
+/// describe its colors rather than its pixel data, so they still apply
+const COLOR_SPACE_CHUNKS: [&[u8; 4]; 4] = [b"sRGB", b"gAMA", b"iCCP", b"cHRM"];
+
+/// A PNG image in its own color type and bit depth
+#[derive(Debug, Clone)]
+pub struct PngImage
+{
+    /// Raw pixels, row by row, [`PngImage::bytes_per_pixel`] bytes each.
+    /// Samples below 8 bits are unpacked to one byte per pixel, and 16-bit
+    /// samples are big endian.
+    pub pixels: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    /// Color type, bit depth and palette of `pixels`
+    pub color: ColorMode,
+    /// Ancillary chunks written along with the image, in file order
+    pub chunks: Vec<Chunk>
+}
+
+fn decode_error(e: lodepng::Error) -> Error
+{
+    Error::Decode(e.to_string())
+}
+
+/// Bytes of one decoded image, whatever its pixel type
+fn image_bytes(image: lodepng::Image) -> Vec<u8>
+{
+    match image
+    {
+        lodepng::Image::RawData(b) => b.buffer,
+        lodepng::Image::Grey(b) => b.buffer.as_bytes().to_vec(),
+        lodepng::Image::Grey16(b) => b.buffer.as_bytes().to_vec(),
+        lodepng::Image::GreyAlpha(b) => b.buffer.as_bytes().to_vec(),
+        lodepng::Image::GreyAlpha16(b) => b.buffer.as_bytes().to_vec(),
+        lodepng::Image::RGBA(b) => b.buffer.as_bytes().to_vec(),
+        lodepng::Image::RGB(b) => b.buffer.as_bytes().to_vec(),
+        lodepng::Image::RGBA16(b) => b.buffer.as_bytes().to_vec(),
+        lodepng::Image::RGB16(b) => b.buffer.as_bytes().to_vec()
+    }
+}
+
+/// Unpacks samples below 8 bits (which lodepng packs without padding between
+/// rows) to one byte per pixel
+fn unpack_bits(packed: &[u8], bitdepth: u32, count: usize) -> Vec<u8>
+{
+    let per_byte = (8 / bitdepth) as usize;
+    let mask = (1u8 << bitdepth) - 1;
+
+    (0..count).map(|i|
+    {
+        let shift = 8 - bitdepth as usize * (i % per_byte + 1);
+        (packed[i / per_byte] >> shift) & mask
+    }).collect()
+}
+
+/// Raw bytes for an RGBA color in the given color mode, if it can represent
+/// it. Colors missing from a palette are added if there's room.
+fn color_bytes(mode: &mut ColorMode, color: RGBA) -> Option<Vec<u8>>
+{
+    let bitdepth = mode.bitdepth();
+    let is_grey = color.r == color.g && color.g == color.b;
+    let opaque = color.a == 255;
+
+    let sample = |v: u8| -> Vec<u8>
+    {
+        if bitdepth == 16 { (v as u16 * 257).to_be_bytes().to_vec() } else { vec![v] }
+    };
+
+    let bytes = match mode.colortype
+    {
+        ColorType::RGBA => [color.r, color.g, color.b, color.a].iter().flat_map(|&v| sample(v)).collect(),
+        ColorType::RGB if opaque => [color.r, color.g, color.b].iter().flat_map(|&v| sample(v)).collect(),
+        ColorType::GREY_ALPHA if is_grey => [color.g, color.a].iter().flat_map(|&v| sample(v)).collect(),
+        ColorType::GREY if is_grey && opaque =>
+        {
+            if bitdepth >= 8
+            {
+                sample(color.g)
+            }
+            else
+            {
+                // Only levels that the lower bit depth hits exactly
+                let max = (1u32 << bitdepth) - 1;
+                if !(color.g as u32 * max).is_multiple_of(255)
+                {
+                    return None;
+                }
+                vec![(color.g as u32 * max / 255) as u8]
+            }
+        },
+        ColorType::PALETTE =>
+        {
+            let index = match mode.palette().iter().position(|&c| c == color)
+            {
+                Some(index) => index,
+                None if mode.palette().len() < 1 << bitdepth =>
+                {
+                    mode.palette_add(color).ok()?;
+                    mode.palette().len() - 1
+                },
+                None => return None
+            };
+            vec![index as u8]
+        },
+        _ => return None
+    };
+
+    return Some(bytes);
+}
+
+/// Ancillary chunks of a PNG file that can be carried to an output with
+/// different pixel data: those whose name marks them safe to copy (with a
+/// lowercase 4th letter), and the color space chunks. tRNS is part of the
+/// color mode.
+fn read_chunks(bytes: &[u8]) -> Vec<Chunk>
+{
+    let mut chunks = Vec::new();
+    let mut placement = Placement::AfterHeader;
+    let mut pos = 8;
+
+    while pos + 12 <= bytes.len()
+    {
+        let length = u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let end = pos + 12 + length;
+        if end > bytes.len()
+        {
+            break;
+        }
+
+        let name = [bytes[pos + 4], bytes[pos + 5], bytes[pos + 6], bytes[pos + 7]];
+        match &name
+        {
+            b"PLTE" => placement = Placement::AfterPalette,
+            b"IDAT" => placement = Placement::AfterData,
+            b"tRNS" => {},
+            _ if name[0].is_ascii_lowercase() && (name[3].is_ascii_lowercase() || COLOR_SPACE_CHUNKS.contains(&&name)) =>
+            {
+                chunks.push(Chunk { name, data: bytes[pos + 8..end - 4].to_vec(), placement });
+            },
+            _ => {}
+        }
+
+        pos = end;
+    }
+
+    return chunks;
+}
+
+/// CRC-32 of a chunk's type and data, as PNG uses it
+fn crc32(bytes: &[u8]) -> u32
+{
+    let mut crc = !0u32;
+    for &b in bytes
+    {
+        crc ^= b as u32;
+        for _ in 0..8
+        {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+
+    return !crc;
+}
+
+fn write_chunk(out: &mut Vec<u8>, chunk: &Chunk)
+{
+    let start = out.len() + 4;
+    out.extend_from_slice(&(chunk.data.len() as u32).to_be_bytes());
+    out.extend_from_slice(&chunk.name);
+    out.extend_from_slice(&chunk.data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Copies an encoded PNG file, adding chunks where their placement says
+fn insert_chunks(bytes: &[u8], chunks: &[&Chunk]) -> Vec<u8>
+{
+    let mut out = bytes[..8].to_vec();
+    let mut placement = Placement::AfterHeader;
+    let mut pos = 8;
+
+    let flush = |out: &mut Vec<u8>, placement: Placement|
+    {
+        for chunk in chunks.iter().filter(|c| c.placement == placement)
+        {
+            write_chunk(out, chunk);
+        }
+    };
+
+    while pos + 12 <= bytes.len()
+    {
+        let length = u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let name = &bytes[pos + 4..pos + 8];
+
+        // Chunks go in before whatever comes after their placement
+        if placement == Placement::AfterHeader && (name == b"PLTE" || name == b"IDAT")
+        {
+            flush(&mut out, placement);
+            placement = Placement::AfterPalette;
+        }
+        if placement == Placement::AfterPalette && name == b"IDAT"
+        {
+            flush(&mut out, placement);
+            placement = Placement::AfterData;
+        }
+        if name == b"IEND"
+        {
+            flush(&mut out, placement);
+        }
+
+        out.extend_from_slice(&bytes[pos..pos + 12 + length]);
+        pos += 12 + length;
+    }
+
+    return out;
+}
+
+fn to_array<const N: usize>(bytes: &[u8]) -> Vec<[u8; N]>
+{
+    bytes.chunks_exact(N).map(|c|
+    {
+        let mut pixel = [0u8; N];
+        pixel.copy_from_slice(c);
+        pixel
+    }).collect()
+}
+
+fn to_array_fill<const N: usize>(fill: &Fill<Vec<u8>>) -> Fill<[u8; N]>
+{
+    Fill { transparent: to_array(&fill.transparent)[0], color: to_array(&fill.color)[0] }
+}
+
+fn extrude_n<const N: usize>(image: &PngImage, options: &Options, fill: &Fill<Vec<u8>>) -> Result<Extruded<Image<u8>>, Error>
+{
+    let extruded = ::extrude_pixels(&to_array::<N>(&image.pixels), image.width, image.height, options, &to_array_fill(fill))?;
+    let buffer = extruded.image.buffer.concat();
+    let image = Image { buffer, width: extruded.image.width, height: extruded.image.height };
+    return Ok(Extruded { image, columns: extruded.columns, rows: extruded.rows, had_partial_tiles: extruded.had_partial_tiles });
+}
+
+fn strip_n<const N: usize>(image: &PngImage, options: &Options, fill: &Fill<Vec<u8>>) -> Result<Stripped<Image<u8>>, Error>
+{
+    let stripped = ::strip_pixels(&to_array::<N>(&image.pixels), image.width, image.height, options, &to_array_fill(fill))?;
+    let buffer = stripped.image.buffer.concat();
+    let image = Image { buffer, width: stripped.image.width, height: stripped.image.height };
+    return Ok(Stripped { image, columns: stripped.columns, rows: stripped.rows, mismatched_tiles: stripped.mismatched_tiles });
+}
+
+impl PngImage
+{
+    /// Reads a PNG file, keeping its color type and bit depth
+    pub fn read(path: &Path) -> Result<PngImage, Error>
+    {
+        let bytes = fs::read(path).map_err(|e| Error::Decode(format!("{} ({:?})", e, path)))?;
+        return PngImage::decode(&bytes);
+    }
+
+    /// Decodes a PNG file in memory, keeping its color type and bit depth
+    pub fn decode(bytes: &[u8]) -> Result<PngImage, Error>
+    {
+        let mut decoder = Decoder::new();
+        decoder.color_convert(false);
+
+        let image = decoder.decode(bytes).map_err(decode_error)?;
+        let (width, height) = match image
+        {
+            lodepng::Image::RawData(ref b) => (b.width, b.height),
+            lodepng::Image::Grey(ref b) => (b.width, b.height),
+            lodepng::Image::Grey16(ref b) => (b.width, b.height),
+            lodepng::Image::GreyAlpha(ref b) => (b.width, b.height),
+            lodepng::Image::GreyAlpha16(ref b) => (b.width, b.height),
+            lodepng::Image::RGBA(ref b) => (b.width, b.height),
+            lodepng::Image::RGB(ref b) => (b.width, b.height),
+            lodepng::Image::RGBA16(ref b) => (b.width, b.height),
+            lodepng::Image::RGB16(ref b) => (b.width, b.height)
+        };
+
+        let color = decoder.info_png().color.clone();
+        let mut pixels = image_bytes(image);
+
+        if color.bitdepth() < 8
+        {
+            pixels = unpack_bits(&pixels, color.bitdepth(), width * height);
+        }
+
+        return Ok(PngImage { pixels, width, height, color, chunks: read_chunks(bytes) });
+    }
+
+    /// Encodes the image, in its own color mode or converted as `output_color` says
+    pub fn encode(&self, output_color: OutputColor) -> Result<Vec<u8>, Error>
+    {
+        let bitdepth = self.color.bitdepth();
+
+        // lodepng can't take packed samples below 8 bits, so hand it 8-bit
+        // samples and let it convert them back
+        let mut raw_color = self.color.clone();
+        let pixels = if bitdepth < 8
+        {
+            raw_color.set_bitdepth(8);
+
+            match self.color.colortype
+            {
+                ColorType::GREY => self.pixels.iter().map(|&v| (v as u32 * 255 / ((1 << bitdepth) - 1)) as u8).collect(),
+                _ => self.pixels.clone()
+            }
+        }
+        else
+        {
+            self.pixels.clone()
+        };
+
+        let mut encoder = Encoder::new();
+        *encoder.info_raw_mut() = raw_color;
+        encoder.info_png_mut().color = self.color.clone();
+
+        match output_color
+        {
+            OutputColor::Keep => encoder.set_auto_convert(false),
+            OutputColor::Auto => encoder.set_auto_convert(true),
+            OutputColor::Convert(colortype, bitdepth) =>
+            {
+                encoder.set_auto_convert(false);
+                let png_color = &mut encoder.info_png_mut().color;
+                png_color.colortype = colortype;
+                png_color.set_bitdepth(bitdepth);
+            }
+        }
+
+        let encoded = encoder.encode(&pixels, self.width, self.height).map_err(|e| Error::Write(e.to_string()))?;
+
+        let chunks: Vec<&Chunk> = self.chunks.iter()
+            .filter(|c| output_color == OutputColor::Keep || !COLOR_CHUNKS.contains(&&c.name))
+            .collect();
+        return Ok(insert_chunks(&encoded, &chunks));
+    }
+
+    /// Writes the image to a PNG file, atomically as [`::write_atomic`] does.
+    /// Returns the color mode the file was written in.
+    pub fn write(&self, path: &Path, output_color: OutputColor) -> Result<ColorMode, Error>
+    {
+        let bytes = self.encode(output_color)?;
+
+        let mut decoder = Decoder::new();
+        decoder.inspect(&bytes).map_err(|e| Error::Write(e.to_string()))?;
+
+        ::write_atomic(path, &bytes)?;
+        return Ok(decoder.info_png().color.clone());
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize
+    {
+        (self.color.bpp() as usize).div_ceil(8)
+    }
+
+    /// Converts the image to RGBA, keeping 16-bit precision if it has it
+    pub fn to_rgba(&self) -> Result<PngImage, Error>
+    {
+        let bitdepth = if self.color.bitdepth() == 16 { 16 } else { 8 };
+
+        let mut decoder = Decoder::new();
+        decoder.info_raw_mut().colortype = ColorType::RGBA;
+        decoder.info_raw_mut().set_bitdepth(bitdepth);
+
+        let image = decoder.decode(self.encode(OutputColor::Keep)?).map_err(decode_error)?;
+        let color = decoder.info_raw().clone();
+        let chunks = self.chunks.iter().filter(|c| !COLOR_CHUNKS.contains(&&c.name)).cloned().collect();
+
+        return Ok(PngImage { pixels: image_bytes(image), width: self.width, height: self.height, color, chunks });
+    }
+
+    /// Replaces all chunks of the given type with one holding `data`
+    pub fn set_chunk(&mut self, name: &[u8; 4], data: Vec<u8>)
+    {
+        self.remove_chunks(name);
+        self.chunks.push(Chunk { name: *name, data, placement: Placement::AfterHeader });
+    }
+
+    pub fn remove_chunks(&mut self, name: &[u8; 4])
+    {
+        self.chunks.retain(|c| &c.name != name);
+    }
+
+    /// Text of the tEXt entry with the given keyword, if there is one
+    pub fn text(&self, keyword: &str) -> Option<String>
+    {
+        let mut prefix = keyword.as_bytes().to_vec();
+        prefix.push(0);
+
+        return self.chunks.iter()
+            .find(|c| &c.name == b"tEXt" && c.data.starts_with(&prefix))
+            .map(|c| String::from_utf8_lossy(&c.data[prefix.len()..]).into_owned());
+    }
+
+    /// Removes the tEXt entries with the given keyword
+    pub fn remove_text(&mut self, keyword: &str)
+    {
+        let mut prefix = keyword.as_bytes().to_vec();
+        prefix.push(0);
+
+        self.chunks.retain(|c| !(&c.name == b"tEXt" && c.data.starts_with(&prefix)));
+    }
+
+    /// Sets a tEXt entry, replacing any with the same keyword
+    pub fn set_text(&mut self, keyword: &str, text: &str)
+    {
+        self.remove_text(keyword);
+
+        let mut data = keyword.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(text.as_bytes());
+        self.chunks.push(Chunk { name: *b"tEXt", data, placement: Placement::AfterData });
+    }
+
+    /// Gutter and padding values in this image's color mode. If the image
+    /// can't represent them, returns `None` (adding to the palette of indexed
+    /// images if there's room).
+    fn fill(&mut self, options: &Options, needs_padding: bool) -> Option<Fill<Vec<u8>>>
+    {
+        let blank = vec![0u8; self.bytes_per_pixel()];
+        let rgba = Fill::rgba(options.extrude_mode);
+
+        let transparent = if needs_padding || options.extrude_mode == ExtrudeMode::Transparent
+        {
+            color_bytes(&mut self.color, rgba.transparent)?
+        }
+        else
+        {
+            blank.clone()
+        };
+
+        let color = match options.extrude_mode
+        {
+            ExtrudeMode::Color(color) => color_bytes(&mut self.color, color)?,
+            _ => blank
+        };
+
+        return Some(Fill { transparent, color });
+    }
+
+    /// Copy of the image along with its fill values, converted to RGBA if its
+    /// own color mode can't represent them
+    fn with_fill(&self, options: &Options, needs_padding: bool) -> Result<(PngImage, Fill<Vec<u8>>), Error>
+    {
+        let mut image = self.clone();
+        if let Some(fill) = image.fill(options, needs_padding)
+        {
+            // The histogram has an entry for each palette color
+            if image.color.palette().len() != self.color.palette().len()
+            {
+                image.remove_chunks(b"hIST");
+            }
+
+            return Ok((image, fill));
+        }
+
+        let mut image = self.to_rgba()?;
+        let fill = image.fill(options, needs_padding).expect("RGBA can represent any fill");
+        return Ok((image, fill));
+    }
+
+    /// Extrudes the tiles of the image as [`::extrude`] does. If the extrude
+    /// mode or padding needs colors the image can't represent, it is
+    /// converted to RGBA first.
+    pub fn extrude(&self, options: &Options) -> Result<Extruded<PngImage>, Error>
+    {
+        let needs_padding = options.partial_tiles == PartialTiles::Pad
+            && (::has_partial_tile(self.width, options.tile_width, options.input_margin, options.input_spacing)
+                || ::has_partial_tile(self.height, options.tile_height, options.input_margin, options.input_spacing));
+
+        let (image, fill) = self.with_fill(options, needs_padding)?;
+
+        let extruded = match image.bytes_per_pixel()
+        {
+            1 => extrude_n::<1>(&image, options, &fill)?,
+            2 => extrude_n::<2>(&image, options, &fill)?,
+            3 => extrude_n::<3>(&image, options, &fill)?,
+            4 => extrude_n::<4>(&image, options, &fill)?,
+            6 => extrude_n::<6>(&image, options, &fill)?,
+            8 => extrude_n::<8>(&image, options, &fill)?,
+            n => return Err(Error::Decode(format!("Unsupported pixel size of {} bytes", n)))
+        };
+
+        let output = PngImage
+        {
+            pixels: extruded.image.buffer,
+            width: extruded.image.width,
+            height: extruded.image.height,
+            color: image.color,
+            chunks: image.chunks
+        };
+        return Ok(Extruded { image: output, columns: extruded.columns, rows: extruded.rows, had_partial_tiles: extruded.had_partial_tiles });
+    }
+
+    /// Removes gutters from the image as [`::strip`] does
+    pub fn strip(&self, options: &Options) -> Result<Stripped<PngImage>, Error>
+    {
+        let (image, fill) = self.with_fill(options, false)?;
+
+        let stripped = match image.bytes_per_pixel()
+        {
+            1 => strip_n::<1>(&image, options, &fill)?,
+            2 => strip_n::<2>(&image, options, &fill)?,
+            3 => strip_n::<3>(&image, options, &fill)?,
+            4 => strip_n::<4>(&image, options, &fill)?,
+            6 => strip_n::<6>(&image, options, &fill)?,
+            8 => strip_n::<8>(&image, options, &fill)?,
+            n => return Err(Error::Decode(format!("Unsupported pixel size of {} bytes", n)))
+        };
+
+        let output = PngImage
+        {
+            pixels: stripped.image.buffer,
+            width: stripped.image.width,
+            height: stripped.image.height,
+            color: image.color,
+            chunks: image.chunks
+        };
+        return Ok(Stripped { image: output, columns: stripped.columns, rows: stripped.rows, mismatched_tiles: stripped.mismatched_tiles });
+    }
+
+    /// Whether the image looks like it was already extruded with these
+    /// options: its size fits whole tiles with gutters, and every gutter
+    /// matches the edge of its tile. Images with flat-colored tile edges can
+    /// look extruded when they aren't.
+    pub fn looks_extruded(&self, options: &Options) -> bool
+    {
+        if options.gutter == 0
+        {
+            return false;
+        }
+
+        let options = Options { partial_tiles: PartialTiles::Error, ..options.clone() };
+        match self.strip(&options)
+        {
+            Ok(stripped) => stripped.columns * stripped.rows > 0 && stripped.mismatched_tiles.is_empty(),
+            Err(_) => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const PALETTE: [RGBA; 16] = [
+        RGBA { r: 0, g: 0, b: 0, a: 255 }, RGBA { r: 255, g: 255, b: 255, a: 255 },
+        RGBA { r: 255, g: 0, b: 0, a: 255 }, RGBA { r: 0, g: 255, b: 0, a: 255 },
+        RGBA { r: 0, g: 0, b: 255, a: 255 }, RGBA { r: 255, g: 255, b: 0, a: 255 },
+        RGBA { r: 0, g: 255, b: 255, a: 255 }, RGBA { r: 255, g: 0, b: 255, a: 255 },
+        RGBA { r: 128, g: 0, b: 0, a: 255 }, RGBA { r: 0, g: 128, b: 0, a: 255 },
+        RGBA { r: 0, g: 0, b: 128, a: 255 }, RGBA { r: 128, g: 128, b: 0, a: 255 },
+        RGBA { r: 0, g: 128, b: 128, a: 255 }, RGBA { r: 128, g: 0, b: 128, a: 255 },
+        RGBA { r: 64, g: 64, b: 64, a: 128 }, RGBA { r: 192, g: 192, b: 192, a: 255 }];
+
+    /// Pixel `i` of a test image, in a color the color type and bit depth
+    /// can hold exactly
+    fn color(colortype: ColorType, bitdepth: u32, i: usize) -> RGBA
+    {
+        let v = (i * 20) as u8;
+
+        match colortype
+        {
+            ColorType::GREY if bitdepth < 8 =>
+            {
+                let levels = 1 << bitdepth;
+                let level = (i % levels) as u8 * (255 / (levels - 1) as u8);
+                RGBA { r: level, g: level, b: level, a: 255 }
+            },
+            ColorType::GREY => RGBA { r: v, g: v, b: v, a: 255 },
+            ColorType::GREY_ALPHA => RGBA { r: v, g: v, b: v, a: 255 - v / 2 },
+            ColorType::RGB => RGBA { r: v, g: 255 - v, b: v / 3, a: 255 },
+            ColorType::RGBA => RGBA { r: v, g: 255 - v, b: v / 3, a: 255 - v / 2 },
+            _ => PALETTE[i % (1 << bitdepth).min(PALETTE.len())]
+        }
+    }
+
+    /// A PNG file of the pixels in the given color type and bit depth
+    fn encode_png(pixels: &[RGBA], width: usize, height: usize, colortype: ColorType, bitdepth: u32, palette: &[RGBA]) -> Vec<u8>
+    {
+        let mut encoder = Encoder::new();
+        encoder.set_auto_convert(false);
+
+        let color = &mut encoder.info_png_mut().color;
+        color.colortype = colortype;
+        color.set_bitdepth(bitdepth);
+        for &entry in palette
+        {
+            color.palette_add(entry).unwrap();
+        }
+
+        return encoder.encode(pixels, width, height).unwrap();
+    }
+
+    fn decode_rgba(bytes: &[u8]) -> Vec<RGBA>
+    {
+        lodepng::decode32(bytes).unwrap().buffer
+    }
+
+    #[test]
+    fn extrude_keeps_every_color_type_and_bit_depth()
+    {
+        let modes = [
+            (ColorType::GREY, 1), (ColorType::GREY, 2), (ColorType::GREY, 4), (ColorType::GREY, 8), (ColorType::GREY, 16),
+            (ColorType::GREY_ALPHA, 8), (ColorType::GREY_ALPHA, 16),
+            (ColorType::RGB, 8), (ColorType::RGB, 16),
+            (ColorType::RGBA, 8), (ColorType::RGBA, 16),
+            (ColorType::PALETTE, 1), (ColorType::PALETTE, 2), (ColorType::PALETTE, 4), (ColorType::PALETTE, 8)];
+
+        // 3x2 tiles, 3 columns and 2 rows, so that rows of samples below 8
+        // bits don't end on a byte boundary
+        let (width, height) = (9, 4);
+
+        for &(colortype, bitdepth) in &modes
+        {
+            let pixels: Vec<RGBA> = (0..width * height).map(|i| color(colortype, bitdepth, i)).collect();
+            let palette = if colortype == ColorType::PALETTE { &PALETTE[..(1 << bitdepth).min(PALETTE.len())] } else { &[][..] };
+            let input = encode_png(&pixels, width, height, colortype, bitdepth, palette);
+
+            let image = PngImage::decode(&input).unwrap();
+            assert_eq!((image.color.colortype, image.color.bitdepth()), (colortype, bitdepth));
+            assert_eq!(image.pixels.len(), width * height * image.bytes_per_pixel());
+
+            for &gutter in &[1, 2]
+            {
+                let mut options = Options::new(3, 2);
+                options.gutter = gutter;
+
+                let extruded = image.extrude(&options).unwrap().image;
+                let output = PngImage::decode(&extruded.encode(OutputColor::Keep).unwrap()).unwrap();
+                assert_eq!((output.color.colortype, output.color.bitdepth()), (colortype, bitdepth), "{:?} {}", colortype, bitdepth);
+
+                let expected = ::extrude(&pixels, width, height, &options).unwrap().image;
+                assert_eq!((output.width, output.height), (expected.width, expected.height));
+                assert_eq!(decode_rgba(&output.encode(OutputColor::Keep).unwrap()), expected.buffer, "{:?} {}, gutter {}", colortype, bitdepth, gutter);
+
+                let stripped = output.strip(&options).unwrap();
+                assert!(stripped.mismatched_tiles.is_empty());
+                assert_eq!(decode_rgba(&stripped.image.encode(OutputColor::Keep).unwrap()), pixels, "{:?} {}, gutter {}", colortype, bitdepth, gutter);
+            }
+        }
+    }
+
+    #[test]
+    fn extrude_keeps_the_low_byte_of_16_bit_samples()
+    {
+        let mut color = ColorMode::new();
+        color.colortype = ColorType::GREY;
+        color.set_bitdepth(16);
+
+        let image = PngImage { pixels: vec![0x12, 0x34], width: 1, height: 1, color, chunks: Vec::new() };
+        let extruded = PngImage::decode(&image.extrude(&Options::new(1, 1)).unwrap().image.encode(OutputColor::Keep).unwrap()).unwrap();
+        assert_eq!(extruded.pixels, [0x12, 0x34].repeat(9));
+    }
+
+    #[test]
+    fn unpack_bits_of_every_depth()
+    {
+        assert_eq!(unpack_bits(&[0b1010_0001, 0b1000_0000], 1, 9), vec![1, 0, 1, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(unpack_bits(&[0b1110_0100, 0b0100_0000], 2, 5), vec![3, 2, 1, 0, 1]);
+        assert_eq!(unpack_bits(&[0xab, 0xc0], 4, 3), vec![0xa, 0xb, 0xc]);
+    }
+
+    #[test]
+    fn fill_colors_grow_the_palette_or_convert_to_rgba()
+    {
+        let transparent = RGBA { r: 0, g: 0, b: 0, a: 0 };
+        let mut options = Options::new(2, 2);
+        options.extrude_mode = ExtrudeMode::Transparent;
+
+        // A 4-bit palette with room for one more color gets the transparent one
+        let pixels: Vec<RGBA> = (0..16).map(|i| PALETTE[i % 15]).collect();
+        let image = PngImage::decode(&encode_png(&pixels, 4, 4, ColorType::PALETTE, 4, &PALETTE[..15])).unwrap();
+
+        let extruded = image.extrude(&options).unwrap().image;
+        assert_eq!((extruded.color.colortype, extruded.color.bitdepth()), (ColorType::PALETTE, 4));
+        assert_eq!(extruded.color.palette().len(), 16);
+        assert_eq!(extruded.color.palette()[15], transparent);
+        assert_eq!(decode_rgba(&extruded.encode(OutputColor::Keep).unwrap()), ::extrude(&pixels, 4, 4, &options).unwrap().image.buffer);
+
+        // A full 16-entry palette can't, so the image becomes RGBA
+        let pixels: Vec<RGBA> = (0..16).map(|i| PALETTE[i]).collect();
+        let image = PngImage::decode(&encode_png(&pixels, 4, 4, ColorType::PALETTE, 4, &PALETTE)).unwrap();
+
+        let extruded = image.extrude(&options).unwrap().image;
+        assert_eq!((extruded.color.colortype, extruded.color.bitdepth()), (ColorType::RGBA, 8));
+        assert_eq!(decode_rgba(&extruded.encode(OutputColor::Keep).unwrap()), ::extrude(&pixels, 4, 4, &options).unwrap().image.buffer);
+
+        // So does grey for a color, while 16-bit RGB keeps its precision
+        options.extrude_mode = ExtrudeMode::Color(RGBA { r: 255, g: 0, b: 0, a: 255 });
+        let pixels: Vec<RGBA> = (0..16).map(|i| color(ColorType::GREY, 8, i)).collect();
+        let image = PngImage::decode(&encode_png(&pixels, 4, 4, ColorType::GREY, 8, &[])).unwrap();
+        assert_eq!(image.extrude(&options).unwrap().image.color.colortype, ColorType::RGBA);
+
+        let image = PngImage::decode(&encode_png(&pixels, 4, 4, ColorType::RGB, 16, &[])).unwrap();
+        let extruded = image.extrude(&options).unwrap().image;
+        assert_eq!((extruded.color.colortype, extruded.color.bitdepth()), (ColorType::RGB, 16));
+        assert_eq!(&extruded.pixels[..6], &[0xff, 0xff, 0, 0, 0, 0]);
+    }
+
+    /// Names of the chunks of a PNG file, in order
+    fn chunk_names(bytes: &[u8]) -> Vec<String>
+    {
+        let mut names = Vec::new();
+        let mut pos = 8;
+        while pos + 12 <= bytes.len()
+        {
+            let length = u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize;
+            names.push(String::from_utf8_lossy(&bytes[pos + 4..pos + 8]).into_owned());
+            pos += 12 + length;
+        }
+
+        return names;
+    }
+
+    fn chunk(name: &[u8; 4], data: &[u8], placement: Placement) -> Chunk
+    {
+        Chunk { name: *name, data: data.to_vec(), placement }
+    }
+
+    #[test]
+    fn crc32_of_chunks()
+    {
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+
+        let mut out = Vec::new();
+        write_chunk(&mut out, &chunk(b"IEND", b"", Placement::AfterData));
+        assert_eq!(out, [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn insert_chunks_where_they_belong()
+    {
+        let encoded = encode_png(&PALETTE[..4], 2, 2, ColorType::PALETTE, 2, &PALETTE[..4]);
+        assert_eq!(chunk_names(&encoded), ["IHDR", "PLTE", "IDAT", "IEND"]);
+
+        let chunks = [
+            chunk(b"tEXt", b"a\0b", Placement::AfterData),
+            chunk(b"gAMA", &[0, 0, 177, 143], Placement::AfterHeader),
+            chunk(b"pHYs", &[0, 0, 11, 19, 0, 0, 11, 19, 1], Placement::AfterPalette)];
+        let with_chunks = insert_chunks(&encoded, &chunks.iter().collect::<Vec<_>>());
+        assert_eq!(chunk_names(&with_chunks), ["IHDR", "gAMA", "PLTE", "pHYs", "IDAT", "tEXt", "IEND"]);
+
+        // Read back, with valid CRCs, in file order
+        let image = PngImage::decode(&with_chunks).unwrap();
+        assert_eq!(image.chunks, [chunks[1].clone(), chunks[2].clone(), chunks[0].clone()]);
+        assert_eq!(decode_rgba(&with_chunks), &PALETTE[..4]);
+    }
+
+    #[test]
+    fn only_chunks_safe_to_copy_are_kept()
+    {
+        let pixels = [PALETTE[0], PALETTE[1], PALETTE[2], PALETTE[0]];
+        let encoded = encode_png(&pixels, 2, 2, ColorType::PALETTE, 2, &PALETTE[..3]);
+        let chunks = [
+            chunk(b"sRGB", &[0], Placement::AfterHeader),
+            chunk(b"iCCP", b"p\0\0x", Placement::AfterHeader),
+            chunk(b"cHRM", &[0; 32], Placement::AfterHeader),
+            chunk(b"hIST", &[0, 1, 0, 2, 0, 3], Placement::AfterPalette),
+            chunk(b"pHYs", &[0, 0, 11, 19, 0, 0, 11, 19, 1], Placement::AfterPalette),
+            chunk(b"acTL", &[0, 0, 0, 1, 0, 0, 0, 0], Placement::AfterPalette),
+            chunk(b"tIME", &[7, 230, 1, 1, 0, 0, 0], Placement::AfterData),
+            chunk(b"tEXt", b"a\0b", Placement::AfterData),
+            chunk(b"prVt", b"private", Placement::AfterData)];
+        let mut image = PngImage::decode(&insert_chunks(&encoded, &chunks.iter().collect::<Vec<_>>())).unwrap();
+
+        let names: Vec<&[u8; 4]> = image.chunks.iter().map(|c| &c.name).collect();
+        assert_eq!(names, [b"sRGB", b"iCCP", b"cHRM", b"pHYs", b"tEXt", b"prVt"]);
+
+        // A histogram added by hand survives extrusion, until the palette grows
+        image.chunks.push(chunks[3].clone());
+        let options = Options::new(2, 2);
+        let extruded = image.extrude(&options).unwrap().image;
+        assert!(extruded.chunks.iter().any(|c| &c.name == b"hIST"));
+
+        let mut transparent = options.clone();
+        transparent.extrude_mode = ExtrudeMode::Transparent;
+        let extruded = image.extrude(&transparent).unwrap().image;
+        assert_eq!(extruded.color.palette().len(), 4);
+        assert!(!extruded.chunks.iter().any(|c| &c.name == b"hIST"));
+        assert!(!chunk_names(&extruded.encode(OutputColor::Keep).unwrap()).contains(&"hIST".to_string()));
+    }
+}
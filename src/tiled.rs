@@ -0,0 +1,352 @@
+//! Tiled tileset (.tsx) files describing extruded or stripped images
+
+use {Error, Options};
+
+/// Layout of a tileset image, as a Tiled tileset describes it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tileset
+{
+    pub name: String,
+    /// Path of the image, relative to the .tsx file
+    pub image_source: String,
+    pub image_width: usize,
+    pub image_height: usize,
+    pub tile_width: usize,
+    pub tile_height: usize,
+    pub margin: usize,
+    pub spacing: usize,
+    pub columns: usize,
+    pub tile_count: usize
+}
+
+/// Escapes text for an XML attribute value
+fn escape(s: &str) -> String
+{
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Start and end (past the `>`) of the first `<name ...>` tag in `xml` at or
+/// after `from`
+fn find_tag(xml: &str, name: &str, from: usize) -> Option<(usize, usize)>
+{
+    let open = format!("<{}", name);
+    let mut pos = from;
+
+    while let Some(i) = xml[pos..].find(&open)
+    {
+        let start = pos + i;
+        let after = xml[start + open.len()..].chars().next();
+
+        if after.is_some_and(|c| c.is_whitespace() || c == '>' || c == '/')
+        {
+            let end = start + xml[start..].find('>')? + 1;
+            return Some((start, end));
+        }
+
+        pos = start + open.len();
+    }
+
+    return None;
+}
+
+/// Undoes [`escape`], and the other entities Tiled may write
+fn unescape(s: &str) -> String
+{
+    s.replace("&quot;", "\"").replace("&apos;", "'").replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+/// Start and end of the value of an attribute in a start tag, inside the quotes
+fn find_attribute(tag: &str, name: &str) -> Option<(usize, usize)>
+{
+    let mut pos = 0;
+
+    while let Some(i) = tag[pos..].find(name)
+    {
+        let start = pos + i;
+        let rest = &tag[start + name.len()..];
+        let preceded_by_space = tag[..start].ends_with(char::is_whitespace);
+
+        if preceded_by_space && rest.starts_with('=')
+        {
+            if let Some(quote) = rest[1..].chars().next().filter(|&q| q == '"' || q == '\'')
+            {
+                let value_start = start + name.len() + 2;
+                if let Some(len) = tag[value_start..].find(quote)
+                {
+                    return Some((value_start, value_start + len));
+                }
+            }
+        }
+
+        pos = start + name.len();
+    }
+
+    return None;
+}
+
+/// Unescaped value of an attribute in a start tag
+fn attribute(tag: &str, name: &str) -> Option<String>
+{
+    find_attribute(tag, name).map(|(start, end)| unescape(&tag[start..end]))
+}
+
+/// Start tag with an attribute set to `value`, replacing its old value or
+/// adding it at the end
+pub(crate) fn set_attribute(tag: &str, name: &str, value: &str) -> String
+{
+    let value = escape(value);
+
+    if let Some((start, end)) = find_attribute(tag, name)
+    {
+        return format!("{}{}{}", &tag[..start], value, &tag[end..]);
+    }
+
+    let end = if tag.ends_with("/>") { tag.len() - 2 } else { tag.len() - 1 };
+    let end = tag[..end].trim_end().len();
+    return format!("{} {}=\"{}\"{}", &tag[..end], name, value, &tag[end..]);
+}
+
+/// The image of the tileset whose start tag ends at `from`: the first
+/// `<image>` before the tileset ends, and before any `<tile>`, which may hold
+/// images of its own in image collection tilesets
+fn tileset_image(xml: &str, from: usize) -> Option<(usize, usize)>
+{
+    let end = xml[from..].find("</tileset>").map_or(xml.len(), |i| from + i);
+    let (start, image_end) = find_tag(xml, "image", from).filter(|&(start, _)| start < end)?;
+
+    if find_tag(xml, "tile", from).is_some_and(|(tile, _)| tile < start)
+    {
+        return None;
+    }
+
+    return Some((start, image_end));
+}
+
+impl Tileset
+{
+    /// Layout of an image extruded or stripped with these options, whose
+    /// tiles have `gutter` pixels around them (0 for stripped images)
+    pub fn new(name: &str, image_source: &str, image_width: usize, image_height: usize, options: &Options, gutter: usize) -> Tileset
+    {
+        let columns = image_width / (options.tile_width + gutter * 2);
+        let rows = image_height / (options.tile_height + gutter * 2);
+
+        Tileset
+        {
+            name: name.into(),
+            image_source: image_source.into(),
+            image_width,
+            image_height,
+            tile_width: options.tile_width,
+            tile_height: options.tile_height,
+            // Tiled counts the margin once at the edge, and the spacing once
+            // between tiles, so gutters of g pixels are a margin of g and a
+            // spacing of 2g
+            margin: gutter,
+            spacing: gutter * 2,
+            columns,
+            tile_count: columns * rows
+        }
+    }
+
+    /// A new .tsx file for the tileset
+    pub fn to_tsx(&self) -> String
+    {
+        let mut tsx = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        tsx.push_str(&format!(
+            "<tileset version=\"1.10\" tiledversion=\"1.10.2\" name=\"{}\" tilewidth=\"{}\" tileheight=\"{}\" spacing=\"{}\" margin=\"{}\" tilecount=\"{}\" columns=\"{}\">\n",
+            escape(&self.name), self.tile_width, self.tile_height, self.spacing, self.margin, self.tile_count, self.columns));
+        tsx.push_str(&format!(" <image source=\"{}\" width=\"{}\" height=\"{}\"/>\n",
+            escape(&self.image_source), self.image_width, self.image_height));
+        tsx.push_str("</tileset>\n");
+        return tsx;
+    }
+
+    /// The tileset element from its start tag to its image tag, with this
+    /// layout and image
+    fn update_element(&self, xml: &str, tileset: (usize, usize), image: (usize, usize), image_source: &str) -> String
+    {
+        let (tileset_start, tileset_end) = tileset;
+        let (image_start, image_end) = image;
+
+        let mut tileset = xml[tileset_start..tileset_end].to_string();
+        for &(name, value) in &[
+            ("tilewidth", self.tile_width),
+            ("tileheight", self.tile_height),
+            ("spacing", self.spacing),
+            ("margin", self.margin),
+            ("tilecount", self.tile_count),
+            ("columns", self.columns)]
+        {
+            tileset = set_attribute(&tileset, name, &value.to_string());
+        }
+
+        let mut image = xml[image_start..image_end].to_string();
+        image = set_attribute(&image, "source", image_source);
+        image = set_attribute(&image, "width", &self.image_width.to_string());
+        image = set_attribute(&image, "height", &self.image_height.to_string());
+
+        return format!("{}{}{}", tileset, &xml[tileset_end..image_start], image);
+    }
+
+    /// Updates the layout and image of an existing .tsx file, keeping
+    /// everything else (name, tile properties, terrains, ...) as it is
+    pub fn update_tsx(&self, tsx: &str) -> Result<String, Error>
+    {
+        let bad_tsx = |what: &str| Error::Tileset(what.into());
+
+        let tileset = find_tag(tsx, "tileset", 0).ok_or_else(|| bad_tsx("no <tileset> element"))?;
+        let image = tileset_image(tsx, tileset.1).ok_or_else(|| bad_tsx("it isn't based on a single image"))?;
+
+        return Ok(format!("{}{}{}", &tsx[..tileset.0], self.update_element(tsx, tileset, image, &self.image_source), &tsx[image.1..]));
+    }
+
+    /// Updates the tilesets of a Tiled map (.tmx) or tileset (.tsx) that use
+    /// this tileset's image. `new_source` is given the image source of each
+    /// tileset as written in the file, and returns what it should be if it
+    /// refers to this image. Returns the new file and how many tilesets
+    /// changed.
+    pub fn update_references<F>(&self, xml: &str, new_source: F) -> (String, usize)
+        where F: Fn(&str) -> Option<String>
+    {
+        let mut updated = String::new();
+        let mut copied = 0;
+        let mut count = 0;
+        let mut pos = 0;
+
+        while let Some(tileset) = find_tag(xml, "tileset", pos)
+        {
+            pos = tileset.1;
+
+            // Tilesets in their own file are <tileset firstgid=".." source=".."/>
+            if xml[tileset.0..tileset.1].ends_with("/>")
+            {
+                continue;
+            }
+
+            let image = match tileset_image(xml, tileset.1)
+            {
+                Some(image) => image,
+                None => continue
+            };
+
+            let source = match attribute(&xml[image.0..image.1], "source").and_then(|source| new_source(&source))
+            {
+                Some(source) => source,
+                None => continue
+            };
+
+            let element = self.update_element(xml, tileset, image, &source);
+            if element != xml[tileset.0..image.1]
+            {
+                updated.push_str(&xml[copied..tileset.0]);
+                updated.push_str(&element);
+                copied = image.1;
+                count += 1;
+            }
+            pos = image.1;
+        }
+
+        updated.push_str(&xml[copied..]);
+        return (updated, count);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// 2x2 tiles of 4x4 pixels, extruded into gutters of 1
+    fn extruded() -> Tileset
+    {
+        Tileset::new("tiles", "tiles_x.png", 12, 12, &Options::new(4, 4), 1)
+    }
+
+    #[test]
+    fn attributes_match_whole_names()
+    {
+        let tag = "<image tilewidth=\"4\" width='12' data-height=\"1\">";
+        assert_eq!(attribute(tag, "width").as_deref(), Some("12"));
+        assert_eq!(attribute(tag, "tilewidth").as_deref(), Some("4"));
+        assert_eq!(attribute(tag, "height"), None);
+
+        assert_eq!(set_attribute(tag, "width", "20"), "<image tilewidth=\"4\" width='20' data-height=\"1\">");
+        assert_eq!(set_attribute("<image tilewidth=\"4\"/>", "width", "20"), "<image tilewidth=\"4\" width=\"20\"/>");
+        assert_eq!(set_attribute("<image source=\"a.png\">", "source", "a&b.png"), "<image source=\"a&amp;b.png\">");
+    }
+
+    #[test]
+    fn update_tsx_keeps_the_rest()
+    {
+        let tsx = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <tileset version=\"1.10\" name=\"mine\" tilewidth=\"4\" tileheight=\"4\" tilecount=\"4\" columns=\"2\">\n \
+            <properties><property name=\"width\" value=\"x\"/></properties>\n \
+            <image source=\"tiles.png\" width=\"8\" height=\"8\"/>\n \
+            <tile id=\"1\" type=\"water\"/>\n\
+            </tileset>\n";
+
+        let updated = extruded().update_tsx(tsx).unwrap();
+        assert_eq!(updated, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <tileset version=\"1.10\" name=\"mine\" tilewidth=\"4\" tileheight=\"4\" tilecount=\"4\" columns=\"2\" spacing=\"2\" margin=\"1\">\n \
+            <properties><property name=\"width\" value=\"x\"/></properties>\n \
+            <image source=\"tiles_x.png\" width=\"12\" height=\"12\"/>\n \
+            <tile id=\"1\" type=\"water\"/>\n\
+            </tileset>\n");
+
+        // A new file and an updated one agree
+        let stripped = Tileset::new("tiles", "tiles.png", 8, 8, &Options::new(4, 4), 0);
+        assert_eq!(extruded().update_tsx(&stripped.to_tsx()).unwrap(), extruded().to_tsx());
+    }
+
+    #[test]
+    fn image_collections_are_left_alone()
+    {
+        let tsx = "<tileset name=\"props\" tilewidth=\"16\" tileheight=\"16\" tilecount=\"1\" columns=\"0\">\n \
+            <tile id=\"0\"><image source=\"tiles.png\" width=\"8\" height=\"8\"/></tile>\n\
+            </tileset>\n";
+
+        match extruded().update_tsx(tsx)
+        {
+            Err(Error::Tileset(message)) => assert!(message.contains("single image"), "{}", message),
+            result => panic!("{:?}", result)
+        }
+
+        assert_eq!(extruded().update_references(tsx, |_| Some("tiles_x.png".into())), (tsx.to_string(), 0));
+    }
+
+    #[test]
+    fn update_references_of_embedded_tilesets()
+    {
+        let tmx = "<map width=\"10\" height=\"10\" tilewidth=\"4\" tileheight=\"4\">\n \
+            <tileset firstgid=\"1\" source=\"other.tsx\"/>\n \
+            <tileset firstgid=\"5\" name=\"a\" tilewidth=\"4\" tileheight=\"4\" tilecount=\"4\" columns=\"2\">\n  \
+            <image source=\"art/tiles.png\" width=\"8\" height=\"8\"/>\n \
+            </tileset>\n \
+            <tileset firstgid=\"9\" name=\"b\" tilewidth=\"4\" tileheight=\"4\" tilecount=\"4\" columns=\"2\">\n  \
+            <image source=\"art/grass.png\" width=\"8\" height=\"8\"/>\n \
+            </tileset>\n \
+            <layer id=\"1\" name=\"ground\" width=\"10\" height=\"10\"/>\n\
+            </map>\n";
+
+        let new_source = |source: &str| match source
+        {
+            "art/tiles.png" => Some("art/tiles_x.png".to_string()),
+            _ => None
+        };
+
+        // Only tileset a changes: the source= reference is a file of its own,
+        // and b uses another image
+        let (updated, count) = extruded().update_references(tmx, new_source);
+        assert_eq!(count, 1);
+        assert_eq!(updated, tmx.replace(
+            "tilecount=\"4\" columns=\"2\">\n  <image source=\"art/tiles.png\" width=\"8\" height=\"8\"/>",
+            "tilecount=\"4\" columns=\"2\" spacing=\"2\" margin=\"1\">\n  <image source=\"art/tiles_x.png\" width=\"12\" height=\"12\"/>"));
+
+        // The map's own width and height aren't touched, and a second update
+        // changes nothing
+        assert!(updated.starts_with("<map width=\"10\" height=\"10\""));
+        let new_source = |source: &str| Some(source.to_string()).filter(|source| source == "art/tiles_x.png");
+        assert_eq!(extruded().update_references(&updated, new_source), (updated.clone(), 0));
+    }
+}
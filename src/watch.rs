@@ -0,0 +1,87 @@
+//! Watching input files and directories for --watch
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use notify::{self, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use tilext::Error;
+
+/// How long to wait for more events after a change before reporting it, as
+/// editors often write a file in several steps
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+pub struct Watch
+{
+    // Stops watching when dropped
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>
+}
+
+fn watch_error(e: notify::Error) -> Error
+{
+    Error::BadArguments(format!("Couldn't watch files: {}", e))
+}
+
+/// Absolute path with symlinks resolved, so paths from events and from the
+/// command line compare equal. Falls back to the path itself if it's gone.
+pub fn canonical(path: &Path) -> PathBuf
+{
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+impl Watch
+{
+    /// Starts watching directories, each recursively or not
+    pub fn new(dirs: &[(PathBuf, bool)]) -> Result<Watch, Error>
+    {
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender).map_err(watch_error)?;
+
+        for &(ref dir, recursive) in dirs
+        {
+            let mode = if recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+            watcher.watch(dir, mode).map_err(|e| Error::BadArguments(format!("Couldn't watch {:?}: {}", dir, e)))?;
+        }
+
+        return Ok(Watch { _watcher: watcher, events });
+    }
+
+    /// Blocks until files are created or written, then returns their
+    /// canonical paths once no more changes come in for a moment
+    pub fn wait_for_changes(&self) -> Result<Vec<PathBuf>, Error>
+    {
+        let mut changed: Vec<PathBuf> = Vec::new();
+
+        loop
+        {
+            let event = if changed.is_empty()
+            {
+                self.events.recv().map_err(|_| Error::BadArguments("File watcher stopped".into()))?
+            }
+            else
+            {
+                match self.events.recv_timeout(DEBOUNCE)
+                {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => return Ok(changed),
+                    Err(RecvTimeoutError::Disconnected) => return Err(Error::BadArguments("File watcher stopped".into()))
+                }
+            };
+
+            let event = event.map_err(watch_error)?;
+            if let EventKind::Create(_) | EventKind::Modify(_) = event.kind
+            {
+                for path in event.paths.iter().filter(|p| p.is_file()).map(|p| canonical(p))
+                {
+                    if !changed.contains(&path)
+                    {
+                        changed.push(path);
+                    }
+                }
+            }
+        }
+    }
+}